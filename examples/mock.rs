@@ -1,59 +1,62 @@
 use serde::Deserialize;
-use serde_json::{Map, Value};
 use std::collections::HashMap;
 use std::env;
 use std::io::{self, BufRead, Write};
 use std::thread::sleep;
 use std::time::Duration;
 
+#[allow(dead_code)]
+#[path = "../src/protocol.rs"]
+mod protocol;
+
+use protocol::{Request, Response};
+
 #[derive(Deserialize)]
-struct Handler {
-    response: Map<String, Value>,
+struct Handler<'a> {
+    #[serde(borrow)]
+    response: Response<'a>,
     #[serde(default)]
     delay: u64,
 }
 
 #[derive(Deserialize)]
-struct Mock {
-    handlers: HashMap<String, Handler>,
+struct Mock<'a> {
+    #[serde(borrow)]
+    handlers: HashMap<String, Handler<'a>>,
 }
 
-impl Mock {
-    fn request(&self, message: Map<String, Value>) -> Map<String, Value> {
-        let action = message
-            .get("action")
-            .and_then(|v| v.as_str())
-            .expect("No action in message");
-        let handler = self.handlers.get(action).expect("No handler for action");
+impl Mock<'_> {
+    fn request(&self, request: &Request) -> &Response<'_> {
+        let handler = self
+            .handlers
+            .get(request.action())
+            .expect("No handler for action");
         let duration = Duration::from_millis(handler.delay);
         sleep(duration);
-        handler.response.clone()
+        &handler.response
     }
 }
 
 pub fn main() {
-    let mock: Mock = serde_json::from_str(
-        env::var("CLIPIPE_MOCK")
-            .expect("CLIPIPE_MOCK not set")
-            .as_ref(),
-    ).expect("Malformed mock specification");
+    let spec = env::var("CLIPIPE_MOCK").expect("CLIPIPE_MOCK not set");
+    let mock: Mock = serde_json::from_str(&spec).expect("Malformed mock specification");
 
     let stdin = io::stdin().lock();
     let mut stdout = io::stdout().lock();
 
     if env::args().any(|arg| arg == "--query") {
-        let mut message = Map::new();
-        message.insert("action".into(), "query".into());
-        writeln!(stdout, "{}", Value::Object(mock.request(message))).expect("Couldn't write to stdout");
+        let response =
+            serde_json::to_string(mock.request(&Request::Query)).expect("Couldn't encode response");
+        writeln!(stdout, "{}", response).expect("Couldn't write to stdout");
         return;
     }
 
     for line in stdin.lines() {
-        let message: Map<String, Value> =
-            serde_json::from_str(line.expect("Could not read stdin").as_ref())
-                .expect("Invalid request JSON");
-        let response = mock.request(message);
-        writeln!(stdout, "{}", Value::Object(response)).expect("Couldn't write to stdout");
+        let line = line.expect("Could not read stdin");
+        let request: Request = serde_json::from_str(&line).expect("Invalid request JSON");
+        let response =
+            serde_json::to_string(mock.request(&request)).expect("Couldn't encode response");
+        writeln!(stdout, "{}", response).expect("Couldn't write to stdout");
         stdout.flush().expect("Couldn't flush stdout");
     }
 }
@@ -45,7 +45,15 @@ local defaults = {
   -- Build clipipe binary from source, if necessary and possible
   build = true,
   -- Download clipipe binary, if necessary and possible
-  download = true
+  download = true,
+  -- Transfer copies and pastes larger than this (bytes) through temporary files instead of the
+  -- pipe.  Not used on WSL, where clipipe.exe can't see Linux paths.
+  spill = 1024 * 1024,
+  -- Protocol encoding: "json", or "msgpack" to avoid escaping clipboard contents
+  encoding = "json",
+  -- Run clipipe as an RPC job and make requests with rpcrequest, instead of polling for
+  -- responses.  Requests then wait as long as clipipe takes, ignoring `timeout`.
+  rpc = false
 }
 
 local config = defaults
@@ -58,7 +66,13 @@ local state = {
   buffer = {},
   request = false,
   response = nil,
-  callback = nil
+  callback = nil,
+  -- Fatal error reported by clipipe before exiting
+  fatal = nil,
+  -- Decoder for MessagePack responses
+  unpacker = nil,
+  -- Channel of the RPC job
+  chan = nil
 }
 
 local function completed_to_source(obj)
@@ -134,6 +148,8 @@ local function reset(proc)
   state.request = false
   state.response = nil
   state.callback = nil
+  state.fatal = nil
+  state.unpacker = nil
 
   if proc then
     local timer = vim.uv.new_timer()
@@ -147,6 +163,106 @@ local function reset(proc)
   end
 end
 
+-- Encode a request for the pipe
+local function encode(request)
+  if config.encoding == "msgpack" then
+    return vim.mpack.encode(request)
+  end
+  return vim.json.encode(request) .. "\n"
+end
+
+-- Handle a decoded response; raw is the undecoded output, for error messages
+local function receive(response, raw)
+  -- We should only receive a response to a request, or a final fatal error
+  if not state.request then
+    if response.fatal then
+      state.fatal = response
+    else
+      notify_error("spurious data", raw)
+    end
+    return
+  end
+  state.request = false
+
+  -- Decide what to do with it
+  local cb = state.callback
+  if cb then
+    state.callback = nil
+    cb(response)
+  else
+    state.response = response
+  end
+end
+
+-- Split JSON lines out of output chunks
+local function receive_json(data)
+  -- Does this chunk complete a line?
+  local idx = string.find(data, "\n", 1, true)
+  if idx then
+    -- Split out data after the newline
+    local pre = string.sub(data, 1, idx)
+    data = string.sub(data, idx + 1)
+    -- Form complete line from chunks
+    table.insert(state.buffer, pre)
+    local stdout = table.concat(state.buffer)
+    -- Save remainder as new buffer table
+    state.buffer = { data }
+
+    -- Parse it
+    local ok, response = pcall(vim.json.decode, stdout)
+    if not ok then
+      response = {
+        success = false,
+        message = "couldn't decode JSON response",
+        source = response
+      }
+    end
+    receive(response, stdout)
+  else
+    table.insert(state.buffer, data)
+  end
+end
+
+-- Decode MessagePack responses from output chunks.  The unpacker keeps any incomplete
+-- response until the next chunk.
+local function receive_msgpack(data)
+  local pos = 1
+  while pos <= #data do
+    local ok, response, next_pos = pcall(state.unpacker, data, pos)
+    if not ok then
+      receive({
+        success = false,
+        message = "couldn't decode MessagePack response",
+        source = response
+      }, data)
+      return
+    end
+    pos = next_pos
+    if type(response) == "table" then
+      receive(response, data)
+    elseif response ~= nil then
+      receive({ success = false, message = "unexpected MessagePack response" }, data)
+    end
+  end
+end
+
+-- Command line for the background process
+local function command()
+  local cmd = { config.path }
+  if is_win and config.keep_line_endings then
+    table.insert(cmd, "--keep-line-endings")
+  end
+  if config.spill and not is_wsl then
+    table.insert(cmd, "--spill=" .. config.spill)
+  end
+  if config.rpc then
+    table.insert(cmd, "--rpc")
+  elseif config.encoding == "msgpack" then
+    table.insert(cmd, "--encoding=msgpack")
+  end
+  return cmd
+end
+
 -- Start background process if not already running
 local function start()
   -- Already in progress?
@@ -175,14 +291,14 @@ local function start()
     return false, "failed to create timer"
   end
 
-  local cmd = { config.path }
-  if is_win and config.keep_line_endings then
-    table.insert(cmd, "--keep-line-endings")
+  if config.encoding == "msgpack" then
+    state.unpacker = vim.mpack.Unpacker()
   end
 
   -- Run clipipe
-  local ok, proc = pcall(vim.system, cmd, {
-      text = true,
+  local ok, proc = pcall(vim.system, command(), {
+      -- MessagePack is binary
+      text = config.encoding ~= "msgpack",
       stdin = true,
       stderr = true,
       -- Output handler
@@ -194,53 +310,19 @@ local function start()
         if not data or not state.proc then
           return
         end
-        -- Does this chunk complete a line?
-        local idx = string.find(data, "\n", 1, true)
-        if idx then
-          -- We should only receive a response to a request
-          if not state.request then
-            notify_error("spurious data", data)
-            return
-          end
-          state.request = false
-
-          -- Split out data after the newline
-          local pre = string.sub(data, 1, idx)
-          data = string.sub(data, idx + 1)
-          -- Form complete line from chunks
-          table.insert(state.buffer, pre)
-          local stdout = table.concat(state.buffer)
-          -- Save remainder as new buffer table
-          state.buffer = { data }
-
-          -- Parse it
-          local ok, response = pcall(vim.json.decode, stdout)
-          if not ok then
-            response = {
-              success = false,
-              message = "couldn't decode JSON response",
-              source = response
-            }
-          end
-
-          -- Decide what to do with it
-          local cb = state.callback
-          if cb then
-            state.callback = nil
-            cb(response)
-          else
-            state.response = response
-          end
+        if state.unpacker then
+          receive_msgpack(data)
         else
-          table.insert(state.buffer, data)
+          receive_json(data)
         end
       end,
     },
     function(obj)
       local cb = state.callback
+      local fatal = state.fatal
       reset()
 
-      local err = completed_to_source(obj)
+      local err = fatal and canon_error(fatal) or completed_to_source(obj)
       if cb then
         cb({ success = false, message = "clipipe terminated", source = err })
       else
@@ -272,7 +354,7 @@ local function start()
 
   local err
   ok, err = pcall(function()
-    proc:write(vim.json.encode { action = "query" } .. "\n")
+    proc:write(encode { action = "query" })
   end)
   if not ok then
     reset(proc)
@@ -289,8 +371,66 @@ local function start()
   return true
 end
 
+-- Start background process as an RPC job if not already running
+local function start_rpc()
+  if state.chan then
+    return true
+  end
+
+  -- Can't start binary if we don't have it
+  if not config.path or config.path == "" then
+    return false, "binary not found"
+  end
+
+  local stderr = {}
+  local ok, chan = pcall(vim.fn.jobstart, command(), {
+    rpc = true,
+    on_stderr = function(_, data)
+      vim.list_extend(stderr, data)
+    end,
+    on_exit = function(_, code)
+      state.chan = nil
+      if code ~= 0 then
+        local source = vim.trim(table.concat(stderr, "\n"))
+        notify_error("terminated", source ~= "" and source or "exit code " .. code)
+      end
+    end
+  })
+  if not ok or chan <= 0 then
+    return false, make_error("failed to start clipipe", ok and "jobstart failed" or chan)
+  end
+  state.chan = chan
+  return true
+end
+
+-- Send a request as an RPC call, get the response
+local function transact_rpc(request)
+  local ok, err = start_rpc()
+  if not ok then
+    return nil, err
+  end
+
+  -- Fields other than the action are passed as a map
+  local params = vim.empty_dict()
+  for key, value in pairs(request) do
+    if key ~= "action" then
+      params[key] = value
+    end
+  end
+  local response
+  ok, response = pcall(vim.rpcrequest, state.chan, "clipipe_" .. request.action, params)
+  if not ok then
+    return nil, make_error("request failed", response)
+  end
+  return response, nil
+end
+
 -- Send a request, get the response
 local function transact(request)
+  if config.rpc then
+    return transact_rpc(request)
+  end
+
   local ok, err = start()
   if not ok then
     return nil, err
@@ -304,7 +444,7 @@ local function transact(request)
 
   -- Write request to pipe
   ok, err = pcall(function()
-    state.proc:write(vim.json.encode(request) .. "\n")
+    state.proc:write(encode(request))
   end)
   if not ok then
     state.request = false
@@ -578,10 +718,63 @@ local reg_to_clipboard = {
   ['*'] = "primary"
 }
 
--- Copy function suitable for g:clipboard
-function M.copy(lines, dest)
-  local data = table.concat(lines, "\n")
-  local request = { action = "copy", data = data, clipboard = reg_to_clipboard[dest] or dest }
+-- Spill files written so far, to name the next one
+local spilled = 0
+
+-- Write data to a new private file, which clipipe removes after reading.  clipipe only reads
+-- files named like its own spill files, in the same directory.
+local function spill(data)
+  local dir = vim.fn.has("linux") == 1 and vim.env.XDG_RUNTIME_DIR or ""
+  if dir == "" then
+    dir = vim.uv.os_tmpdir()
+  end
+  spilled = spilled + 1
+  local path = string.format("%s/clipipe-%d-%d.txt", dir, vim.fn.getpid(), spilled)
+  local fd, err = vim.uv.fs_open(path, "wx", 384)
+  if not fd then
+    return nil, err
+  end
+  local ok, write_err = vim.uv.fs_write(fd, data)
+  vim.uv.fs_close(fd)
+  if not ok then
+    os.remove(path)
+    return nil, write_err
+  end
+  return path
+end
+
+-- Read and remove a file clipipe returned a paste in
+local function unspill(path)
+  local file, err = io.open(path, "rb")
+  if not file then
+    return nil, err
+  end
+  local data = file:read("a")
+  file:close()
+  os.remove(path)
+  return data
+end
+
+-- Copy function suitable for g:clipboard.  clipipe joins the lines, which end with an empty
+-- line for linewise and blockwise registers.
+function M.copy(lines, dest, regtype)
+  local request = {
+    action = "copy",
+    lines = lines,
+    regtype = regtype,
+    clipboard = reg_to_clipboard[dest] or dest,
+  }
+  local size = #lines
+  for _, line in ipairs(lines) do
+    size = size + #line
+  end
+  if config.spill and not is_wsl and size > config.spill then
+    local path = spill(table.concat(lines, "\n"))
+    if path then
+      request.lines = nil
+      request.file = path
+    end
+  end
   local response, err = transact(request)
   if not response then
     if err == IN_PROGRESS then
@@ -592,9 +785,9 @@ function M.copy(lines, dest)
   end
 end
 
--- Paste function suitable for g:clipboard
+-- Paste function suitable for g:clipboard, returning the lines and register type
 function M.paste(source)
-  local request = { action = "paste", clipboard = reg_to_clipboard[source] or source }
+  local request = { action = "paste", lines = true, clipboard = reg_to_clipboard[source] or source }
   local response, err = transact(request)
   if not response then
     if err == IN_PROGRESS then
@@ -604,7 +797,16 @@ function M.paste(source)
     end
     return {}
   end
-  return vim.split(response.data, "\n", { plain = true })
+  local lines = response.lines
+  if response.file then
+    local data, read_err = unspill(response.file)
+    if not data then
+      notify_error("paste failed", make_error("couldn't read " .. response.file, read_err))
+      return {}
+    end
+    lines = vim.split(data, "\n", { plain = true })
+  end
+  return { lines, response.regtype }
 end
 
 -- Plugin setup
@@ -627,8 +829,8 @@ function M.enable()
   vim.g.clipboard = {
     name = "clipipe",
     copy = {
-      ["+"] = function(lines) M.copy(lines, '+') end,
-      ["*"] = function(lines) M.copy(lines, '*') end,
+      ["+"] = function(lines, regtype) M.copy(lines, '+', regtype) end,
+      ["*"] = function(lines, regtype) M.copy(lines, '*', regtype) end,
     },
     paste = {
       ["+"] = function() return M.paste('+') end,
@@ -0,0 +1,300 @@
+// Latency and throughput self-benchmark (`clipipe bench`)
+use serde_json::{Map, Value};
+use std::env;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::Result;
+use crate::backend;
+use crate::clipboard::{Backend, Dest, Source};
+use crate::compat;
+use crate::options::{Options, usage_error};
+use crate::protocol::{Request, Response};
+
+const DEFAULT_SIZES: &[usize] = &[16, 1024, 64 * 1024, 1024 * 1024];
+const DEFAULT_ITERATIONS: usize = 20;
+const PERCENTILES: &[usize] = &[50, 90, 99];
+
+// Benchmark settings
+struct Settings {
+    sizes: Vec<usize>,
+    iterations: usize,
+    json: bool,
+}
+
+// Timings for one operation at one payload size
+struct Series {
+    path: &'static str,
+    op: &'static str,
+    size: usize,
+    samples: Vec<Duration>,
+}
+
+impl Series {
+    fn new(path: &'static str, op: &'static str, size: usize) -> Series {
+        Series {
+            path,
+            op,
+            size,
+            samples: vec![],
+        }
+    }
+
+    fn time<T>(&mut self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let start = Instant::now();
+        let res = f()?;
+        self.samples.push(start.elapsed());
+        Ok(res)
+    }
+
+    fn percentile(&self, p: usize) -> Duration {
+        let mut sorted = self.samples.clone();
+        sorted.sort();
+        let idx = (p * (sorted.len() - 1) + 50) / 100;
+        sorted[idx]
+    }
+
+    fn max(&self) -> Duration {
+        self.samples.iter().copied().max().unwrap_or_default()
+    }
+
+    // Throughput at median latency, in MiB/s
+    fn throughput(&self) -> f64 {
+        self.size as f64 / (1024.0 * 1024.0) / self.percentile(50).as_secs_f64()
+    }
+}
+
+fn parse_size(arg: &str) -> Result<usize> {
+    let (num, mult) = match arg.as_bytes().last() {
+        Some(b'k' | b'K') => (&arg[..arg.len() - 1], 1024),
+        Some(b'm' | b'M') => (&arg[..arg.len() - 1], 1024 * 1024),
+        _ => (arg, 1),
+    };
+    Ok(num
+        .parse::<usize>()
+        .map_err(|_| format!("Invalid payload size: {}", arg))?
+        * mult)
+}
+
+fn parse_args<'a>(mut args: impl Iterator<Item = &'a String>) -> Result<Settings> {
+    let mut settings = Settings {
+        sizes: DEFAULT_SIZES.into(),
+        iterations: DEFAULT_ITERATIONS,
+        json: false,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--json" => settings.json = true,
+            "--sizes" => {
+                let sizes = args.next().ok_or("Missing argument for --sizes")?;
+                settings.sizes = sizes.split(',').map(parse_size).collect::<Result<_>>()?;
+            }
+            "--iterations" => {
+                let n = args.next().ok_or("Missing argument for --iterations")?;
+                settings.iterations = n
+                    .parse()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| format!("Invalid iteration count: {}", n))?;
+            }
+            _ => return Err(usage_error(format!("Invalid bench argument: {}", arg))),
+        }
+    }
+    Ok(settings)
+}
+
+// Printable payload of given size, with regular line breaks
+fn payload(size: usize) -> String {
+    const CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789 \"\\{}";
+    (0..size)
+        .map(|i| {
+            if i % 80 == 79 {
+                '\n'
+            } else {
+                CHARS[i % CHARS.len()] as char
+            }
+        })
+        .collect()
+}
+
+fn check(expected: &str, actual: &str) -> Result<()> {
+    if expected != actual {
+        return Err(format!(
+            "Pasted data doesn't match copied data ({} bytes vs {} bytes)",
+            actual.len(),
+            expected.len()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// Time copy and paste calls directly against the backend.  Pastes go through a second backend,
+// since the one that copied would answer from memory rather than the display server.
+fn bench_backend(options: &Options, settings: &Settings, results: &mut Vec<Series>) -> Result<()> {
+    let mut backend = backend::Backend::new(options)?;
+    let mut reader = backend::Backend::new(options)?;
+    for &size in &settings.sizes {
+        let data = payload(size);
+        let mut copy = Series::new("backend", "copy", size);
+        let mut paste = Series::new("backend", "paste", size);
+        for _ in 0..settings.iterations {
+            copy.time(|| Ok(backend.copy(Dest::Clipboard, &data, None)?))?;
+            let pasted = paste.time(|| Ok(reader.paste(Source::Clipboard)?))?;
+            check(&data, &pasted.data)?;
+        }
+        results.push(copy);
+        results.push(paste);
+    }
+    Ok(())
+}
+
+// Time requests through the JSON protocol loop of a child clipipe process
+fn bench_protocol(options: &Options, settings: &Settings, results: &mut Vec<Series>) -> Result<()> {
+    // The child reads the user's configuration, which mustn't spill or limit the payload
+    let largest = settings.sizes.iter().max().copied().unwrap_or(0);
+    let mut child = Command::new(env::current_exe()?)
+        .args(options.backend_args())
+        // Don't leave the benchmark payload held when it exits
+        .arg("--no-keep-alive")
+        .arg(format!("--spill={}", largest))
+        .arg(format!("--max-paste={}", largest))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut input = BufReader::new(child.stdout.take().ok_or("No stdout for child")?);
+    let mut output = BufWriter::new(child.stdin.take().ok_or("No stdin for child")?);
+
+    // Returns pasted data, if any
+    let mut request = |req: &Request| -> Result<Option<String>> {
+        serde_json::to_writer(&mut output, req)?;
+        writeln!(output)?;
+        output.flush()?;
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Err("clipipe exited unexpectedly".into());
+        }
+        let res: Response = serde_json::from_str(&line)?;
+        if !res.success {
+            return Err(format!("Request failed: {}", line.trim_end()).into());
+        }
+        Ok(res.data.map(|data| data.into_owned()))
+    };
+
+    let res = (|| {
+        let mut query = Series::new("protocol", "query", 0);
+        for _ in 0..settings.iterations {
+            query.time(|| request(&Request::Query))?;
+        }
+        results.push(query);
+
+        for &size in &settings.sizes {
+            let data = payload(size);
+            let copy_req = Request::Copy {
+                clipboard: Dest::Clipboard,
+                data: Some(data.as_str().into()),
+                file: None,
+                lines: None,
+                regtype: None,
+            };
+            let paste_req = Request::Paste {
+                clipboard: Source::Clipboard,
+                if_changed_since: None,
+                lines: false,
+            };
+            let mut copy = Series::new("protocol", "copy", size);
+            let mut paste = Series::new("protocol", "paste", size);
+            for _ in 0..settings.iterations {
+                copy.time(|| request(&copy_req))?;
+                let res = paste.time(|| request(&paste_req))?;
+                check(&data, res.as_deref().unwrap_or(""))?;
+            }
+            results.push(copy);
+            results.push(paste);
+        }
+        Ok(())
+    })();
+
+    drop(output);
+    child.wait()?;
+    res
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+fn to_json(results: &[Series]) -> Value {
+    let series = results
+        .iter()
+        .map(|s| {
+            let mut obj = Map::new();
+            obj.insert("path".into(), s.path.into());
+            obj.insert("op".into(), s.op.into());
+            obj.insert("size".into(), s.size.into());
+            obj.insert("iterations".into(), s.samples.len().into());
+            for p in PERCENTILES {
+                obj.insert(format!("p{}_ms", p), millis(s.percentile(*p)).into());
+            }
+            obj.insert("max_ms".into(), millis(s.max()).into());
+            if s.size > 0 {
+                obj.insert("mib_per_s".into(), s.throughput().into());
+            }
+            obj.into()
+        })
+        .collect::<Vec<Value>>();
+
+    let mut res = Map::new();
+    res.insert("version".into(), crate::VERSION.into());
+    res.insert("results".into(), series.into());
+    res.into()
+}
+
+fn write_text(results: &[Series], out: &mut impl Write) -> io::Result<()> {
+    write!(out, "{:<10} {:<6} {:>10}", "path", "op", "size")?;
+    for p in PERCENTILES {
+        write!(out, " {:>9}", format!("p{}", p))?;
+    }
+    writeln!(out, " {:>9} {:>10}", "max", "MiB/s")?;
+
+    for s in results {
+        write!(out, "{:<10} {:<6} {:>10}", s.path, s.op, s.size)?;
+        for p in PERCENTILES {
+            write!(out, " {:>7.2}ms", millis(s.percentile(*p)))?;
+        }
+        write!(out, " {:>7.2}ms", millis(s.max()))?;
+        if s.size > 0 {
+            writeln!(out, " {:>10.1}", s.throughput())?;
+        } else {
+            writeln!(out, " {:>10}", "-")?;
+        }
+    }
+    Ok(())
+}
+
+// Run benchmark and print results
+pub fn run(options: &Options, args: &[String]) -> Result<()> {
+    let settings = parse_args(args.iter())?;
+
+    // Don't clobber the user's clipboard.  It's restored from a `clipipe hold` process, since
+    // the selection would otherwise go away with our backend.
+    let saved = backend::Backend::new(options)?
+        .paste(Source::Clipboard)?
+        .data;
+
+    let mut results = vec![];
+    let res = bench_backend(options, &settings, &mut results)
+        .and_then(|_| bench_protocol(options, &settings, &mut results));
+
+    compat::spawn_hold(options, Dest::Clipboard, &saved, None)?;
+    res?;
+
+    let mut stdout = io::stdout().lock();
+    if settings.json {
+        writeln!(stdout, "{}", to_json(&results))?;
+    } else {
+        write_text(&results, &mut stdout)?;
+    }
+    Ok(())
+}
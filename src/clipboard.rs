@@ -1,23 +1,48 @@
-// Source of a paste
-pub enum Source {
-    Default,
-    Primary,
-    Clipboard,
-}
-
-// Destination of a copy
-pub enum Dest {
-    Default,
-    Primary,
-    Clipboard,
-    Both,
-}
+use crate::options::Options;
+pub use crate::protocol::{Dest, Source};
 
 // Data returned from a paste
 pub struct Data {
     pub data: String,
     // Mime type, if known.  Strictly advisory, only text is supported.
     pub mime: Option<String>,
+    // Vim motion type, if the data came from a register
+    pub motion: Option<Motion>,
+}
+
+// How a Vim register's text is put: as characters, whole lines or a block
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Motion {
+    Char,
+    Line,
+    Block,
+}
+
+impl Motion {
+    // Parse a Neovim register type, e.g. `v`, `V`, or `b` or `^V` with an optional width
+    pub fn from_regtype(regtype: &str) -> Option<Motion> {
+        match regtype.chars().next()? {
+            'v' | 'c' => Some(Motion::Char),
+            'V' | 'l' => Some(Motion::Line),
+            'b' | '\u{16}' => Some(Motion::Block),
+            _ => None,
+        }
+    }
+
+    pub fn regtype(self) -> &'static str {
+        match self {
+            Motion::Char => "v",
+            Motion::Line => "V",
+            Motion::Block => "b",
+        }
+    }
+}
+
+// Convert pasted bytes to text, without copying them if they're valid UTF-8
+#[cfg(target_os = "linux")]
+pub fn text(bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes)
+        .unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned())
 }
 
 // Information about an error
@@ -93,9 +118,48 @@ impl std::convert::From<std::io::Error> for Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+// Request or paste over the configured maximum size
+#[derive(Debug)]
+pub struct PayloadTooLarge {
+    // Size, if it was read in full
+    pub size: Option<usize>,
+    pub max: usize,
+}
+
+impl std::fmt::Display for PayloadTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        match self.size {
+            Some(size) => write!(f, "Payload too large ({} bytes, maximum {})", size, self.max),
+            None => write!(f, "Payload too large (maximum {} bytes)", self.max),
+        }
+    }
+}
+
+impl std::error::Error for PayloadTooLarge {}
+
 pub trait Backend {
-    // Copy to clipboard.  Note that data is not Data; all copies are text/plain
-    fn copy(&mut self, dest: Dest, data: &str) -> Result<()>;
+    // Copy to clipboard, with the Vim motion type if it came from a register.  Note that data
+    // is not Data; all copies are text/plain
+    fn copy(&mut self, dest: Dest, data: &str, motion: Option<Motion>) -> Result<()>;
     // Paste from clipboard
     fn paste(&mut self, source: Source) -> Result<Data>;
+    // Apply reloaded options which don't require reconnecting
+    fn configure(&mut self, _options: &Options) {}
+    // Hand contents we own over to a clipboard manager before exiting.  Returns whether anything
+    // was handed off.
+    fn handoff(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+    // Selections we own which would be lost if we exited, with their contents and motion type
+    fn owned(&mut self) -> Result<Vec<(Dest, String, Option<Motion>)>> {
+        Ok(vec![])
+    }
+    fn owns_selection(&mut self) -> Result<bool> {
+        Ok(!self.owned()?.is_empty())
+    }
+    // Hold any global clipboard lock across several operations, until `unlock`
+    fn lock(&mut self) -> Result<()> {
+        Ok(())
+    }
+    fn unlock(&mut self) {}
 }
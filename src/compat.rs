@@ -0,0 +1,653 @@
+// Compatibility personalities for common clipboard command line tools.
+//
+// When the binary is invoked through a symlink named after one of these tools, it parses the
+// common subset of that tool's flags and performs a single copy or paste, so scripts which
+// hardcode e.g. `xclip -selection clipboard -o` work on any supported system.
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::Path;
+use std::process;
+
+use crate::Result;
+use crate::backend;
+use crate::clipboard::{Backend, Dest, Motion, Source};
+use crate::options::{Options, usage_error};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Personality {
+    Xclip,
+    Xsel,
+    WlCopy,
+    WlPaste,
+    Pbcopy,
+    Pbpaste,
+}
+
+impl Personality {
+    // Determine personality from the name we were invoked as
+    pub fn detect() -> Option<Personality> {
+        let arg0 = env::args_os().next()?;
+        Some(match Path::new(&arg0).file_stem()?.to_str()? {
+            "xclip" => Personality::Xclip,
+            "xsel" => Personality::Xsel,
+            "wl-copy" => Personality::WlCopy,
+            "wl-paste" => Personality::WlPaste,
+            "pbcopy" => Personality::Pbcopy,
+            "pbpaste" => Personality::Pbpaste,
+            _ => return None,
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Personality::Xclip => "xclip",
+            Personality::Xsel => "xsel",
+            Personality::WlCopy => "wl-copy",
+            Personality::WlPaste => "wl-paste",
+            Personality::Pbcopy => "pbcopy",
+            Personality::Pbpaste => "pbpaste",
+        }
+    }
+
+    fn usage(self) -> &'static str {
+        match self {
+            Personality::Xclip => {
+                "[-i|-o] [-selection primary|clipboard] [-t TARGET] [-f] [-r] [FILE...]"
+            }
+            Personality::Xsel => "[-i|-o|-a|-c] [-p|-b] [-n] [--trim]",
+            Personality::WlCopy => "[-p] [--regular] [-n] [-f] [-c] [-t TYPE] [TEXT...]",
+            Personality::WlPaste => "[-p] [-n] [-l] [-t TYPE]",
+            Personality::Pbcopy | Personality::Pbpaste => "",
+        }
+    }
+}
+
+// Selection a tool operates on
+#[derive(Clone, Copy)]
+enum Selection {
+    Primary,
+    Clipboard,
+    Both,
+}
+
+impl Selection {
+    fn dest(self) -> Dest {
+        match self {
+            Selection::Primary => Dest::Primary,
+            Selection::Clipboard => Dest::Clipboard,
+            Selection::Both => Dest::Both,
+        }
+    }
+
+    fn source(self) -> Source {
+        match self {
+            Selection::Primary => Source::Primary,
+            Selection::Clipboard | Selection::Both => Source::Clipboard,
+        }
+    }
+}
+
+// What to do, decoded from the tool-specific flags
+struct Invocation {
+    selection: Selection,
+    // Write current contents to stdout
+    output: bool,
+    // Set contents from input
+    input: bool,
+    // Clear contents instead of reading input
+    clear: bool,
+    // Prepend current contents to input
+    append: bool,
+    // Echo input to stdout
+    filter: bool,
+    // Remove a trailing newline from input
+    trim_newline: bool,
+    // Add a trailing newline to output
+    newline: bool,
+    // Print offered types instead of contents
+    list_types: bool,
+    // Serve the selection without detaching from the caller
+    foreground: bool,
+    // Input files (`-` is stdin)
+    files: Vec<OsString>,
+    // Literal input text
+    text: Option<String>,
+}
+
+impl Invocation {
+    fn new(selection: Selection) -> Invocation {
+        Invocation {
+            selection,
+            output: false,
+            input: false,
+            clear: false,
+            append: false,
+            filter: false,
+            trim_newline: false,
+            newline: false,
+            list_types: false,
+            foreground: false,
+            files: vec![],
+            text: None,
+        }
+    }
+}
+
+// Parsed command line argument
+enum Arg {
+    // Option, by canonical name, with value if it takes one
+    Flag(&'static str, Option<OsString>),
+    Positional(OsString),
+}
+
+// Option specification: short name, long name, takes a value
+type Spec = (Option<char>, &'static str, bool);
+
+fn unsupported(flag: &str) -> Box<dyn std::error::Error> {
+    format!("unsupported option: {}", flag).into()
+}
+
+fn missing_value(flag: &str) -> Box<dyn std::error::Error> {
+    format!("option requires an argument: {}", flag).into()
+}
+
+// Parse GNU-style arguments: clustered short options, `--long`, `--long=value` and `--`
+fn parse_gnu(args: Vec<OsString>, specs: &[Spec]) -> Result<Vec<Arg>> {
+    let mut res = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.to_str().filter(|a| a.starts_with('-') && a.len() > 1) else {
+            res.push(Arg::Positional(arg));
+            continue;
+        };
+        if flag == "--" {
+            res.extend(args.by_ref().map(Arg::Positional));
+        } else if let Some(long) = flag.strip_prefix("--") {
+            let (name, value) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(OsString::from(value))),
+                None => (long, None),
+            };
+            let &(_, name, takes_value) = specs
+                .iter()
+                .find(|(_, n, _)| *n == name)
+                .ok_or_else(|| unsupported(flag))?;
+            let value = match (takes_value, value) {
+                (true, None) => Some(args.next().ok_or_else(|| missing_value(flag))?),
+                (false, Some(_)) => {
+                    return Err(format!("option takes no argument: --{}", name).into());
+                }
+                (_, value) => value,
+            };
+            res.push(Arg::Flag(name, value));
+        } else {
+            for (i, c) in flag.char_indices().skip(1) {
+                let &(_, name, takes_value) = specs
+                    .iter()
+                    .find(|(s, _, _)| *s == Some(c))
+                    .ok_or_else(|| unsupported(&format!("-{}", c)))?;
+                if takes_value {
+                    let rest = &flag[i + c.len_utf8()..];
+                    let value = if rest.is_empty() {
+                        args.next().ok_or_else(|| missing_value(flag))?
+                    } else {
+                        rest.into()
+                    };
+                    res.push(Arg::Flag(name, Some(value)));
+                    break;
+                }
+                res.push(Arg::Flag(name, None));
+            }
+        }
+    }
+    Ok(res)
+}
+
+// Parse xclip-style arguments: single dash, long names which may be abbreviated as long as they
+// are unambiguous
+fn parse_xclip_args(args: Vec<OsString>, specs: &[Spec]) -> Result<Vec<Arg>> {
+    let mut res = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.to_str().filter(|a| a.starts_with('-') && a.len() > 1) else {
+            res.push(Arg::Positional(arg));
+            continue;
+        };
+        let prefix = &flag[1..];
+        let exact = specs.iter().find(|(_, n, _)| *n == prefix);
+        let mut candidates = specs.iter().filter(|(_, n, _)| n.starts_with(prefix));
+        let &(_, name, takes_value) = match (exact, candidates.next(), candidates.next()) {
+            (Some(spec), _, _) | (None, Some(spec), None) => spec,
+            (None, None, _) => return Err(unsupported(flag)),
+            (None, Some(_), Some(_)) => return Err(format!("ambiguous option: {}", flag).into()),
+        };
+        let value = if takes_value {
+            Some(args.next().ok_or_else(|| missing_value(flag))?)
+        } else {
+            None
+        };
+        res.push(Arg::Flag(name, value));
+    }
+    Ok(res)
+}
+
+fn value_str(value: &Option<OsString>) -> Result<&str> {
+    value
+        .as_deref()
+        .and_then(OsStr::to_str)
+        .ok_or_else(|| "invalid option argument".into())
+}
+
+// Only text is supported, so accept only text types/targets
+fn check_type(value: &Option<OsString>) -> Result<()> {
+    let ty = value_str(value)?;
+    if ty.starts_with("text/") || ["UTF8_STRING", "STRING", "TEXT"].contains(&ty) {
+        Ok(())
+    } else {
+        Err(format!("unsupported type: {}", ty).into())
+    }
+}
+
+fn parse_xclip(args: Vec<OsString>) -> Result<Option<Invocation>> {
+    const SPECS: &[Spec] = &[
+        (None, "in", false),
+        (None, "out", false),
+        (None, "filter", false),
+        (None, "selection", true),
+        (None, "target", true),
+        (None, "rmlastnl", false),
+        (None, "loops", true),
+        (None, "quiet", false),
+        (None, "silent", false),
+        (None, "verbose", false),
+        (None, "noutf8", false),
+        (None, "help", false),
+        (None, "version", false),
+    ];
+
+    let mut inv = Invocation::new(Selection::Primary);
+    inv.input = true;
+    for arg in parse_xclip_args(args, SPECS)? {
+        match arg {
+            Arg::Flag("in", _) => (inv.input, inv.output) = (true, false),
+            Arg::Flag("out", _) => (inv.input, inv.output) = (false, true),
+            Arg::Flag("filter", _) => inv.filter = true,
+            Arg::Flag("rmlastnl", _) => inv.trim_newline = true,
+            Arg::Flag("target", value) => check_type(&value)?,
+            Arg::Flag("selection", value) => {
+                inv.selection = match value_str(&value)?.chars().next() {
+                    Some('p') => Selection::Primary,
+                    Some('c') => Selection::Clipboard,
+                    _ => {
+                        return Err(format!("unsupported selection: {}", value_str(&value)?).into());
+                    }
+                }
+            }
+            Arg::Flag("help", _) => return Ok(None),
+            Arg::Flag("version", _) => {
+                println!("clipipe {} (xclip compatible)", crate::VERSION);
+                return Ok(Some(Invocation::new(Selection::Primary)));
+            }
+            // We serve until another client takes over regardless of loop count
+            Arg::Flag(_, _) => (),
+            Arg::Positional(file) => inv.files.push(file),
+        }
+    }
+    Ok(Some(inv))
+}
+
+fn parse_xsel(args: Vec<OsString>) -> Result<Option<Invocation>> {
+    const SPECS: &[Spec] = &[
+        (Some('a'), "append", false),
+        (Some('i'), "input", false),
+        (Some('o'), "output", false),
+        (Some('c'), "clear", false),
+        (Some('p'), "primary", false),
+        (Some('b'), "clipboard", false),
+        (Some('k'), "keep", false),
+        (Some('n'), "nodetach", false),
+        (Some('t'), "selectionTimeout", true),
+        (Some('l'), "logfile", true),
+        (Some('v'), "verbose", false),
+        (Some('q'), "quiet", false),
+        (None, "trim", false),
+        (Some('h'), "help", false),
+        (None, "version", false),
+    ];
+
+    let mut inv = Invocation::new(Selection::Primary);
+    let mut explicit = false;
+    for arg in parse_gnu(args, SPECS)? {
+        match arg {
+            Arg::Flag("append", _) => (inv.input, inv.append, explicit) = (true, true, true),
+            Arg::Flag("input", _) => (inv.input, explicit) = (true, true),
+            Arg::Flag("output", _) => (inv.output, explicit) = (true, true),
+            Arg::Flag("clear", _) => (inv.clear, inv.input, explicit) = (true, true, true),
+            Arg::Flag("primary", _) => inv.selection = Selection::Primary,
+            Arg::Flag("clipboard", _) => inv.selection = Selection::Clipboard,
+            Arg::Flag("nodetach", _) => inv.foreground = true,
+            Arg::Flag("trim", _) => inv.trim_newline = true,
+            Arg::Flag("help", _) => return Ok(None),
+            Arg::Flag("version", _) => {
+                println!("clipipe {} (xsel compatible)", crate::VERSION);
+                return Ok(Some(Invocation::new(Selection::Primary)));
+            }
+            Arg::Flag(_, _) => (),
+            Arg::Positional(arg) => {
+                return Err(format!("unexpected argument: {}", arg.to_string_lossy()).into());
+            }
+        }
+    }
+
+    // Like xsel, infer direction from whether stdin/stdout are terminals
+    if !explicit {
+        inv.input = !io::stdin().is_terminal();
+        inv.output = !io::stdout().is_terminal();
+    }
+    Ok(Some(inv))
+}
+
+fn parse_wl_copy(args: Vec<OsString>) -> Result<Option<Invocation>> {
+    const SPECS: &[Spec] = &[
+        (Some('p'), "primary", false),
+        (None, "regular", false),
+        (Some('n'), "trim-newline", false),
+        (Some('f'), "foreground", false),
+        (Some('c'), "clear", false),
+        (Some('t'), "type", true),
+        (Some('h'), "help", false),
+        (Some('v'), "version", false),
+    ];
+
+    let mut inv = Invocation::new(Selection::Clipboard);
+    inv.input = true;
+    let (mut primary, mut regular) = (false, false);
+    let mut text: Vec<String> = vec![];
+    for arg in parse_gnu(args, SPECS)? {
+        match arg {
+            Arg::Flag("primary", _) => primary = true,
+            Arg::Flag("regular", _) => regular = true,
+            Arg::Flag("trim-newline", _) => inv.trim_newline = true,
+            Arg::Flag("foreground", _) => inv.foreground = true,
+            Arg::Flag("clear", _) => inv.clear = true,
+            Arg::Flag("type", value) => check_type(&value)?,
+            Arg::Flag("help", _) => return Ok(None),
+            Arg::Flag("version", _) => {
+                println!("clipipe {} (wl-copy compatible)", crate::VERSION);
+                return Ok(Some(Invocation::new(Selection::Clipboard)));
+            }
+            Arg::Flag(_, _) => (),
+            Arg::Positional(arg) => text.push(
+                arg.into_string()
+                    .map_err(|_| "text argument is not valid UTF-8")?,
+            ),
+        }
+    }
+
+    inv.selection = match (primary, regular) {
+        (true, true) => Selection::Both,
+        (true, false) => Selection::Primary,
+        (false, _) => Selection::Clipboard,
+    };
+    if !text.is_empty() {
+        inv.text = Some(text.join(" "));
+    }
+    Ok(Some(inv))
+}
+
+fn parse_wl_paste(args: Vec<OsString>) -> Result<Option<Invocation>> {
+    const SPECS: &[Spec] = &[
+        (Some('p'), "primary", false),
+        (Some('n'), "no-newline", false),
+        (Some('l'), "list-types", false),
+        (Some('t'), "type", true),
+        (Some('h'), "help", false),
+        (Some('v'), "version", false),
+    ];
+
+    let mut inv = Invocation::new(Selection::Clipboard);
+    inv.output = true;
+    inv.newline = true;
+    for arg in parse_gnu(args, SPECS)? {
+        match arg {
+            Arg::Flag("primary", _) => inv.selection = Selection::Primary,
+            Arg::Flag("no-newline", _) => inv.newline = false,
+            Arg::Flag("list-types", _) => inv.list_types = true,
+            Arg::Flag("type", value) => check_type(&value)?,
+            Arg::Flag("help", _) => return Ok(None),
+            Arg::Flag("version", _) => {
+                println!("clipipe {} (wl-paste compatible)", crate::VERSION);
+                return Ok(Some(Invocation::new(Selection::Clipboard)));
+            }
+            Arg::Flag(_, _) => (),
+            Arg::Positional(arg) => {
+                return Err(format!("unexpected argument: {}", arg.to_string_lossy()).into());
+            }
+        }
+    }
+    Ok(Some(inv))
+}
+
+fn parse_pb(personality: Personality, args: Vec<OsString>) -> Result<Option<Invocation>> {
+    let mut inv = Invocation::new(Selection::Clipboard);
+    if personality == Personality::Pbcopy {
+        inv.input = true;
+    } else {
+        inv.output = true;
+    }
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            // Only text is supported
+            Some("-pboard") | Some("-Prefer") if args.next().is_some() => (),
+            Some("-help") => return Ok(None),
+            _ => return Err(unsupported(&arg.to_string_lossy())),
+        }
+    }
+    Ok(Some(inv))
+}
+
+// Gather input from text argument, files or stdin
+fn read_input(inv: &Invocation) -> Result<String> {
+    if let Some(text) = &inv.text {
+        return Ok(text.clone());
+    }
+
+    let mut data = vec![];
+    if inv.files.is_empty() {
+        io::stdin().lock().read_to_end(&mut data)?;
+    }
+    for file in &inv.files {
+        if file == "-" {
+            io::stdin().lock().read_to_end(&mut data)?;
+        } else {
+            File::open(file)
+                .map_err(|e| format!("{}: {}", file.to_string_lossy(), e))?
+                .read_to_end(&mut data)?;
+        }
+    }
+    Ok(String::from_utf8(data)?)
+}
+
+// Like the real tools, return to the caller once the selection is set and keep serving it from a
+// detached background process.  Returns a function the child calls when the selection is set.
+// Must be called before any threads are started.
+#[cfg(target_os = "linux")]
+fn detach() -> Result<impl FnOnce()> {
+    use std::os::fd::{AsRawFd, FromRawFd};
+
+    let mut fds = [0; 2];
+    // SAFETY: valid array for two file descriptors
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // SAFETY: the file descriptors were just created and are owned by nothing else
+    let (mut reader, mut writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    // SAFETY: callers fork before starting any threads, including the backend's, which the
+    // child wouldn't have
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error().into()),
+        0 => {
+            drop(reader);
+            // Start a new session, and don't keep the caller's pipes open (e.g. `$(xclip)`).
+            // Keep stderr until the selection is set so errors are reported.
+            let null = File::options().read(true).write(true).open("/dev/null")?;
+            // SAFETY: plain system calls on valid file descriptors
+            unsafe {
+                libc::setsid();
+                for fd in 0..=1 {
+                    libc::dup2(null.as_raw_fd(), fd);
+                }
+            }
+            Ok(move || {
+                let _ = writer.write_all(b"\n");
+                // SAFETY: as above
+                unsafe { libc::dup2(null.as_raw_fd(), 2) };
+            })
+        }
+        _ => {
+            // Exit once the child has set the selection, or failed to and closed the pipe
+            drop(writer);
+            let mut buf = [0; 1];
+            std::process::exit(match reader.read(&mut buf) {
+                Ok(1) => 0,
+                _ => 1,
+            })
+        }
+    }
+}
+
+// Set the selection, serving it until replaced where that is necessary
+#[cfg(target_os = "linux")]
+fn copy(dest: Dest, data: &str, foreground: bool) -> Result<()> {
+    let options = Options::from_env()?;
+    if foreground {
+        Ok(backend::Backend::new(&options)?.hold(dest, data, None, || ())?)
+    } else {
+        // The backend serves from threads, so it's started in the child
+        let ready = detach()?;
+        Ok(backend::Backend::new(&options)?.hold(dest, data, None, ready)?)
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn copy(dest: Dest, data: &str, _foreground: bool) -> Result<()> {
+    Ok(backend::Backend::new(&Options::from_env()?)?.copy(dest, data, None)?)
+}
+
+// Serve stdin as the given selection from a background process (`clipipe hold`), with the
+// register type if given.  Used by the daemon to keep its selections available after it exits.
+pub fn hold(options: &Options, args: &[String]) -> Result<()> {
+    let (dest, motion) = match args {
+        [name] => (Dest::parse(name), None),
+        [name, regtype] => match Motion::from_regtype(regtype) {
+            Some(motion) => (Dest::parse(name), Some(motion)),
+            None => (None, None),
+        },
+        _ => (None, None),
+    };
+    let dest =
+        dest.ok_or_else(|| usage_error("Usage: clipipe hold clipboard|primary [REGTYPE]".into()))?;
+    let mut data = String::new();
+    io::stdin().lock().read_to_string(&mut data)?;
+
+    #[cfg(target_os = "linux")]
+    {
+        let ready = detach()?;
+        Ok(backend::Backend::new(options)?.hold(dest, &data, motion, ready)?)
+    }
+    #[cfg(target_os = "windows")]
+    Ok(backend::Backend::new(options)?.copy(dest, &data, motion)?)
+}
+
+// Start a `clipipe hold` process for a selection, returning once it owns it
+pub fn spawn_hold(options: &Options, dest: Dest, data: &str, motion: Option<Motion>) -> Result<()> {
+    let mut child = process::Command::new(env::current_exe()?)
+        .args(options.backend_args())
+        .args(["hold", dest.name()])
+        .args(motion.map(Motion::regtype))
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(data.as_bytes())?;
+    }
+    match child.wait()? {
+        status if status.success() => Ok(()),
+        status => Err(format!("clipipe hold failed ({})", status).into()),
+    }
+}
+
+fn paste(source: Source) -> Result<String> {
+    Ok(backend::Backend::new(&Options::from_env()?)?
+        .paste(source)?
+        .data)
+}
+
+fn execute(inv: Invocation) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+
+    if inv.list_types {
+        writeln!(stdout, "text/plain;charset=utf-8")?;
+        return Ok(());
+    }
+
+    if inv.output {
+        let data = paste(inv.selection.source())?;
+        stdout.write_all(data.as_bytes())?;
+        if inv.newline && !data.is_empty() {
+            stdout.write_all(b"\n")?;
+        }
+        stdout.flush()?;
+    }
+
+    if inv.input {
+        let mut data = if inv.clear {
+            String::new()
+        } else {
+            read_input(&inv)?
+        };
+        if inv.append {
+            data.insert_str(0, &paste(inv.selection.source())?);
+        }
+        if inv.trim_newline && data.ends_with('\n') {
+            data.pop();
+        }
+        if inv.filter {
+            stdout.write_all(data.as_bytes())?;
+            stdout.flush()?;
+        }
+        drop(stdout);
+        copy(inv.selection.dest(), &data, inv.foreground)?;
+    }
+    Ok(())
+}
+
+// Run as the given tool, returning the process exit code
+pub fn main(personality: Personality) -> i32 {
+    let args = env::args_os().skip(1).collect();
+    let res = match personality {
+        Personality::Xclip => parse_xclip(args),
+        Personality::Xsel => parse_xsel(args),
+        Personality::WlCopy => parse_wl_copy(args),
+        Personality::WlPaste => parse_wl_paste(args),
+        Personality::Pbcopy | Personality::Pbpaste => parse_pb(personality, args),
+    }
+    .and_then(|inv| match inv {
+        Some(inv) => execute(inv),
+        None => {
+            println!("Usage: {} {}", personality.name(), personality.usage());
+            Ok(())
+        }
+    });
+
+    match res {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("{}: {}", personality.name(), err);
+            1
+        }
+    }
+}
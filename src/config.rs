@@ -0,0 +1,153 @@
+// Configuration file (`$XDG_CONFIG_HOME/clipipe/config.toml` by default)
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::Result;
+use crate::log::Level;
+use crate::options::{BackendKind, LineEndings, Options, Oversized};
+
+// Error loading or applying the configuration file
+#[derive(Debug)]
+pub struct Error(pub Box<dyn std::error::Error>);
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source()
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    // Backends to try, in order
+    backends: Option<Vec<String>>,
+    // Paste timeout (ms)
+    timeout: Option<u64>,
+    // Maximum request size (bytes)
+    max_request: Option<usize>,
+    // Maximum paste size (bytes)
+    max_paste: Option<usize>,
+    // What to do with larger pastes
+    oversized: Option<String>,
+    // Return larger pastes in a temporary file (bytes)
+    spill: Option<usize>,
+    line_endings: Option<String>,
+    // Preferred paste MIME types
+    mime_priority: Option<Vec<String>>,
+    log_file: Option<PathBuf>,
+    log_level: Option<String>,
+    // Log clipboard contents instead of redacting them
+    log_contents: Option<bool>,
+    // Hand clipboard contents to a clipboard manager on shutdown
+    handoff: Option<bool>,
+    // Keep owned selections available after exiting
+    keep_alive: Option<bool>,
+    // Exit after inactivity (seconds)
+    idle_timeout: Option<u64>,
+    // Copy coalescing window (ms)
+    coalesce: Option<u64>,
+}
+
+// Default configuration file location
+fn default_path() -> Option<PathBuf> {
+    #[cfg(target_os = "windows")]
+    let dir = env::var_os("APPDATA").map(PathBuf::from);
+    #[cfg(not(target_os = "windows"))]
+    let dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+    Some(dir?.join("clipipe").join("config.toml"))
+}
+
+impl Config {
+    // Load configuration from given path, or the default path if it exists
+    pub fn load(path: Option<&Path>) -> Result<Option<Config>> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(None),
+            },
+        };
+
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if !required && err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(format!("Couldn't read {}: {}", path.display(), err).into()),
+        };
+        toml::from_str(&text)
+            .map(Some)
+            .map_err(|err| format!("Invalid configuration in {}: {}", path.display(), err).into())
+    }
+
+    // Apply settings to options
+    pub fn apply(self, options: &mut Options) -> Result<()> {
+        if let Some(backends) = self.backends {
+            options.backends = BackendKind::parse_chain(backends.iter().map(String::as_str))
+                .ok_or_else(|| {
+                    format!("Invalid backends in configuration: {}", backends.join(", "))
+                })?;
+        }
+        if let Some(timeout) = self.timeout {
+            options.timeout = Duration::from_millis(timeout);
+        }
+        if let Some(max) = self.max_request {
+            options.max_request = Some(max);
+        }
+        if let Some(max) = self.max_paste {
+            options.max_paste = Some(max);
+        }
+        if let Some(policy) = self.oversized {
+            options.oversized = Oversized::parse(&policy).ok_or_else(|| {
+                format!("Invalid oversized paste policy in configuration: {}", policy)
+            })?;
+        }
+        if let Some(spill) = self.spill {
+            options.spill = Some(spill);
+        }
+        if let Some(policy) = self.line_endings {
+            options.line_endings = LineEndings::parse(&policy).ok_or_else(|| {
+                format!("Invalid line ending policy in configuration: {}", policy)
+            })?;
+        }
+        if let Some(mime_priority) = self.mime_priority {
+            options.mime_priority = mime_priority;
+        }
+        if let Some(path) = self.log_file {
+            options.log_file = Some(path);
+        }
+        if let Some(level) = self.log_level {
+            options.log_level = Some(
+                Level::parse(&level)
+                    .ok_or_else(|| format!("Invalid log level in configuration: {}", level))?,
+            );
+        }
+        if let Some(contents) = self.log_contents {
+            options.log_contents = contents;
+        }
+        if let Some(handoff) = self.handoff {
+            options.handoff = handoff;
+        }
+        if let Some(keep_alive) = self.keep_alive {
+            options.keep_alive = keep_alive;
+        }
+        if let Some(timeout) = self.idle_timeout {
+            options.idle_timeout = Some(Duration::from_secs(timeout));
+        }
+        if let Some(coalesce) = self.coalesce {
+            options.coalesce = Duration::from_millis(coalesce);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,331 @@
+// Environment diagnostics (`clipipe doctor`)
+use serde_json::{Map, Value};
+use std::env;
+use std::io::{self, Write};
+
+use crate::Result;
+use crate::backend;
+use crate::clipboard::{Backend, Dest, Source};
+use crate::compat;
+use crate::options::{BackendKind, Options, usage_error};
+
+// Environment variables relevant to backend selection
+#[cfg(target_os = "linux")]
+const ENV_VARS: &[&str] = &[
+    "WAYLAND_DISPLAY",
+    "DISPLAY",
+    "XDG_SESSION_TYPE",
+    "XDG_CURRENT_DESKTOP",
+    "XDG_RUNTIME_DIR",
+    "WSL_DISTRO_NAME",
+];
+#[cfg(target_os = "windows")]
+const ENV_VARS: &[&str] = &["SESSIONNAME"];
+
+// Data used for copy/paste round trips
+const PROBE: &str = "clipipe doctor probe";
+
+// Outcome of a single check
+struct Check {
+    name: String,
+    result: std::result::Result<String, String>,
+    // Suggested fix on failure
+    fix: Option<&'static str>,
+}
+
+#[derive(Default)]
+struct Report {
+    checks: Vec<Check>,
+}
+
+impl Report {
+    fn pass(&mut self, name: impl Into<String>, detail: impl Into<String>) {
+        self.checks.push(Check {
+            name: name.into(),
+            result: Ok(detail.into()),
+            fix: None,
+        });
+    }
+
+    fn fail(&mut self, name: impl Into<String>, detail: impl Into<String>, fix: &'static str) {
+        self.checks.push(Check {
+            name: name.into(),
+            result: Err(detail.into()),
+            fix: Some(fix),
+        });
+    }
+
+    // Copy to each selection with one backend and paste from it with another, restoring the
+    // previous contents afterwards.  The paste goes through a separate connection because a
+    // backend answers from memory while it owns the selection, which wouldn't test the display
+    // server.  The contents are restored from a `clipipe hold` process, since the selection
+    // would otherwise go away with the backend.
+    fn round_trip<B: Backend>(
+        &mut self,
+        options: &Options,
+        kind: BackendKind,
+        backend: &mut B,
+        reader: &mut B,
+    ) {
+        let label = kind.name();
+        let mut options = options.clone();
+        options.backends = vec![kind];
+        for name in ["clipboard", "primary"] {
+            let check = format!("{} {} round trip", label, name);
+            let saved = reader.paste(Self::source(name)).map(|data| data.data);
+            let res = backend
+                .copy(Self::dest(name), PROBE, None)
+                .and_then(|_| reader.paste(Self::source(name)));
+            match res {
+                Ok(data) if data.data == PROBE => self.pass(check, "ok"),
+                Ok(data) => self.fail(
+                    check,
+                    format!("pasted {:?} instead of probe data", data.data),
+                    "another application (such as a clipboard manager) may be rewriting the \
+                     selection",
+                ),
+                Err(err) => self.fail(
+                    check,
+                    chain(&err),
+                    "the selection couldn't be set or read; see the error for details",
+                ),
+            }
+            if let Ok(saved) = saved
+                && let Err(err) = compat::spawn_hold(&options, Self::dest(name), &saved, None)
+            {
+                self.fail(
+                    format!("{} {} restore", label, name),
+                    chain(&*err),
+                    "the previous contents of the selection were lost",
+                );
+            }
+        }
+    }
+
+    fn source(name: &str) -> Source {
+        match name {
+            "primary" => Source::Primary,
+            _ => Source::Clipboard,
+        }
+    }
+
+    fn dest(name: &str) -> Dest {
+        match name {
+            "primary" => Dest::Primary,
+            _ => Dest::Clipboard,
+        }
+    }
+
+    fn ok(&self) -> bool {
+        self.checks.iter().all(|c| c.result.is_ok())
+    }
+}
+
+// Format error with its source chain on one line
+fn chain(error: &dyn std::error::Error) -> String {
+    let mut res = error.to_string();
+    let mut source = error.source();
+    while let Some(err) = source {
+        res.push_str(": ");
+        res.push_str(&err.to_string());
+        source = err.source();
+    }
+    res
+}
+
+#[cfg(target_os = "linux")]
+fn diagnose(options: &Options, report: &mut Report) {
+    use backend::{WaylandBackend, X11Backend};
+    use wl_clipboard_rs::utils::{PrimarySelectionCheckError, is_primary_selection_supported};
+
+    let have = |var| env::var(var).is_ok_and(|v| !v.is_empty());
+
+    match backend::Backend::new(options) {
+        Ok(backend::Backend::Wayland(_)) => report.pass("backend selection", "wayland"),
+        Ok(backend::Backend::X11(_)) => report.pass("backend selection", "x11"),
+        Err(err) => report.fail(
+            "backend selection",
+            chain(&err),
+            "run from a graphical session, or set WAYLAND_DISPLAY or DISPLAY (over SSH, enable \
+             X11 forwarding with `ssh -X`)",
+        ),
+    }
+
+    if have("WAYLAND_DISPLAY") {
+        let connected = match is_primary_selection_supported() {
+            Ok(primary) => {
+                report.pass("wayland connection", "ok");
+                report.pass("wayland data-control protocol", "available");
+                if primary {
+                    report.pass("wayland primary selection", "supported");
+                } else {
+                    report.fail(
+                        "wayland primary selection",
+                        "not supported",
+                        "the compositor doesn't offer primary selection through data-control; \
+                         the primary register will use the regular clipboard",
+                    );
+                }
+                true
+            }
+            Err(PrimarySelectionCheckError::NoSeats) => {
+                report.pass("wayland connection", "ok");
+                report.fail(
+                    "wayland seats",
+                    "no seats",
+                    "the compositor has no seats (input devices), so there is no clipboard",
+                );
+                false
+            }
+            Err(err @ PrimarySelectionCheckError::MissingProtocol) => {
+                report.pass("wayland connection", "ok");
+                report.fail(
+                    "wayland data-control protocol",
+                    chain(&err),
+                    "the compositor doesn't support data-control (e.g. GNOME); unset \
+                     WAYLAND_DISPLAY to use the X11 backend through XWayland",
+                );
+                false
+            }
+            Err(err) => {
+                report.fail(
+                    "wayland connection",
+                    chain(&err),
+                    "check that WAYLAND_DISPLAY and XDG_RUNTIME_DIR refer to a running compositor",
+                );
+                false
+            }
+        };
+        if connected {
+            match WaylandBackend::new(options)
+                .and_then(|wl| Ok((wl, WaylandBackend::new(options)?)))
+            {
+                Ok((mut wl, mut reader)) => {
+                    report.round_trip(options, BackendKind::Wayland, &mut wl, &mut reader)
+                }
+                Err(err) => report.fail(
+                    "wayland backend",
+                    chain(&err),
+                    "check that the compositor supports data-control",
+                ),
+            }
+        }
+    }
+
+    if have("DISPLAY") {
+        match X11Backend::new(options).and_then(|x11| Ok((x11, X11Backend::new(options)?))) {
+            Ok((mut x11, mut reader)) => {
+                report.pass("x11 connection and atoms", "ok");
+                report.round_trip(options, BackendKind::X11, &mut x11, &mut reader);
+            }
+            Err(err) => report.fail(
+                "x11 connection and atoms",
+                chain(&err),
+                "check that DISPLAY refers to a running X server and that access is permitted \
+                 (xauth or xhost)",
+            ),
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn diagnose(options: &Options, report: &mut Report) {
+    let backends = backend::Backend::new(options)
+        .and_then(|backend| Ok((backend, backend::Backend::new(options)?)));
+    match backends {
+        Ok((mut backend, mut reader)) => {
+            report.pass("backend selection", "windows");
+            report.round_trip(options, BackendKind::Windows, &mut backend, &mut reader);
+        }
+        Err(err) => report.fail(
+            "backend selection",
+            chain(&err),
+            "the Windows clipboard is unavailable; see the error for details",
+        ),
+    }
+}
+
+fn to_json(report: &Report) -> Value {
+    let mut vars = Map::new();
+    for var in ENV_VARS {
+        vars.insert((*var).into(), env::var(var).ok().into());
+    }
+
+    let checks = report
+        .checks
+        .iter()
+        .map(|check| {
+            let mut obj = Map::new();
+            obj.insert("name".into(), check.name.clone().into());
+            obj.insert("ok".into(), check.result.is_ok().into());
+            let (Ok(detail) | Err(detail)) = &check.result;
+            obj.insert("detail".into(), detail.clone().into());
+            if let Some(fix) = check.fix {
+                obj.insert("fix".into(), fix.into());
+            }
+            obj.into()
+        })
+        .collect::<Vec<Value>>();
+
+    let mut res = Map::new();
+    res.insert("version".into(), crate::VERSION.into());
+    res.insert("environment".into(), vars.into());
+    res.insert("checks".into(), checks.into());
+    res.insert("ok".into(), report.ok().into());
+    res.into()
+}
+
+fn write_text(report: &Report, out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "clipipe {}", crate::VERSION)?;
+    writeln!(out)?;
+    writeln!(out, "Environment:")?;
+    for var in ENV_VARS {
+        match env::var(var) {
+            Ok(value) => writeln!(out, "  {}={}", var, value)?,
+            Err(_) => writeln!(out, "  {} (unset)", var)?,
+        }
+    }
+    writeln!(out)?;
+    writeln!(out, "Checks:")?;
+    for check in &report.checks {
+        match &check.result {
+            Ok(detail) => writeln!(out, "  [ok]   {}: {}", check.name, detail)?,
+            Err(detail) => writeln!(out, "  [FAIL] {}: {}", check.name, detail)?,
+        }
+        if let Some(fix) = check.fix {
+            writeln!(out, "         fix: {}", fix)?;
+        }
+    }
+    writeln!(out)?;
+    if report.ok() {
+        writeln!(out, "No problems found.")
+    } else {
+        writeln!(out, "Problems found, see above.")
+    }
+}
+
+// Run diagnostics and print report
+pub fn run(options: &Options, args: &[String]) -> Result<()> {
+    let mut json = false;
+    for arg in args {
+        match arg.as_ref() {
+            "--json" => json = true,
+            _ => return Err(usage_error(format!("Invalid doctor argument: {}", arg))),
+        }
+    }
+
+    let mut report = Report::default();
+    diagnose(options, &mut report);
+
+    let mut stdout = io::stdout().lock();
+    if json {
+        writeln!(stdout, "{}", to_json(&report))?;
+    } else {
+        write_text(&report, &mut stdout)?;
+    }
+    // Exit with a failure status, so scripts can check the result
+    if !report.ok() {
+        return Err("problems found".into());
+    }
+    Ok(())
+}
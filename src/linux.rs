@@ -1,22 +1,42 @@
-use crate::clipboard::{self, Data, Dest, Error, ErrorDetail, Result, Source};
+use crate::clipboard::{self, Data, Dest, Error, ErrorDetail, Motion, Result, Source};
+use crate::log::{self, Level};
+use crate::options::{self, BackendKind};
+use crate::vim;
+use crate::wayland::{self, Selection};
+use crate::x11;
 
 use std::env;
 use std::io::Read;
-use std::time::Duration;
+use std::os::fd::AsRawFd;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use wl_clipboard_rs::{
     copy::{
-        ClipboardType as CopyClipboardType, Error as CopyError, MimeType as CopyMimeType, Options,
-        Source as CopySource,
+        ClipboardType as CopyClipboardType, Error as CopyError, MimeSource,
+        MimeType as CopyMimeType, Options, Source as CopySource,
     },
     paste::{
-        get_contents, ClipboardType as PasteClipboardType, Error as PasteError,
-        MimeType as PasteMimeType, Seat,
+        ClipboardType as PasteClipboardType, Error as PasteError, MimeType as PasteMimeType, Seat,
+        get_contents, get_mime_types,
     },
-    utils::is_primary_selection_supported,
+    utils::{PrimarySelectionCheckError, is_primary_selection_supported},
 };
 
-use x11_clipboard::{error::Error as X11Error, Atom, Clipboard as X11Clipboard};
+use x11_clipboard::{Atom, Clipboard as X11Clipboard, error::Error as X11Error};
+use x11rb::connection::Connection as _;
+use x11rb::errors::{ConnectionError as X11ConnectionError, ReplyError as X11ReplyError};
+use x11rb::protocol::Event as X11Event;
+use x11rb::protocol::xproto::{AtomEnum, ConnectionExt as _, Property};
+
+// Interval at which to check whether we still own a held X11 selection
+const HOLD_POLL_INTERVAL: Duration = Duration::from_millis(250);
+// Room for the header of Vim's format when limiting the size of a paste
+const VIM_HEADER_SIZE: usize = 64;
+// How long to wait for a clipboard manager to take over the clipboard
+const HANDOFF_TIMEOUT: Duration = Duration::from_secs(2);
+const HANDOFF_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 impl std::convert::From<CopyError> for Error {
     fn from(value: CopyError) -> Error {
@@ -30,22 +50,128 @@ impl std::convert::From<PasteError> for Error {
     }
 }
 
+impl std::convert::From<PrimarySelectionCheckError> for Error {
+    fn from(value: PrimarySelectionCheckError) -> Error {
+        Error::new_with_source(ErrorDetail::System, value)
+    }
+}
+
 impl std::convert::From<X11Error> for Error {
     fn from(value: X11Error) -> Error {
         Error::new_with_source(ErrorDetail::System, value)
     }
 }
 
+impl std::convert::From<X11ConnectionError> for Error {
+    fn from(value: X11ConnectionError) -> Error {
+        X11Error::from(value).into()
+    }
+}
+
+impl std::convert::From<X11ReplyError> for Error {
+    fn from(value: X11ReplyError) -> Error {
+        X11Error::from(value).into()
+    }
+}
+
 pub struct WaylandBackend {
     // Does server support primary selection operations?
     primary_supported: bool,
+    // Preferred paste MIME types
+    mime_priority: Vec<String>,
+    // Maximum paste size, to avoid reading more than that
+    max_paste: Option<usize>,
+    // In-process selection server, if the compositor supports it
+    server: Option<wayland::Server>,
+    // Last data copied to each selection without the server, to tell whether we still own it,
+    // with its motion type
+    regular: Option<(String, Option<Motion>)>,
+    primary: Option<(String, Option<Motion>)>,
 }
 
 impl WaylandBackend {
-    fn new() -> WaylandBackend {
-        WaylandBackend {
-            primary_supported: is_primary_selection_supported().is_ok(),
+    pub fn new(options: &options::Options) -> Result<WaylandBackend> {
+        let server = match wayland::Server::new() {
+            Ok(server) => Some(server),
+            Err(err) => {
+                log::error(Level::Info, "serving selections per copy", &err);
+                None
+            }
+        };
+        Ok(WaylandBackend {
+            primary_supported: match server {
+                Some(ref server) => server.primary_supported(),
+                None => match is_primary_selection_supported() {
+                    Ok(supported) => supported,
+                    // Can't tell without a seat
+                    Err(PrimarySelectionCheckError::NoSeats) => false,
+                    Err(err) => return Err(err.into()),
+                },
+            },
+            mime_priority: options.mime_priority.clone(),
+            max_paste: options.max_paste,
+            server,
+            regular: None,
+            primary: None,
+        })
+    }
+
+    // Without the server, the serving thread can't be asked whether its offer is still current,
+    // so compare the selection contents with what we last copied to it
+    fn owned(&mut self) -> Result<Vec<(Dest, String, Option<Motion>)>> {
+        if let Some(ref server) = self.server {
+            return Ok([
+                (Dest::Clipboard, Selection::Regular),
+                (Dest::Primary, Selection::Primary),
+            ]
+            .into_iter()
+            .filter_map(|(dest, selection)| {
+                let (data, motion) = server.owned(selection)?;
+                Some((dest, data.to_string(), motion))
+            })
+            .collect());
+        }
+        let mut owned = vec![];
+        for (dest, ty, copied) in [
+            (Dest::Clipboard, PasteClipboardType::Regular, &self.regular),
+            (Dest::Primary, PasteClipboardType::Primary, &self.primary),
+        ] {
+            let Some((copied, motion)) = copied else {
+                continue;
+            };
+            match get_contents(ty, Seat::Unspecified, PasteMimeType::Text) {
+                Ok((mut pipe, _)) => {
+                    let mut contents = vec![];
+                    pipe.read_to_end(&mut contents)?;
+                    if contents == copied.as_bytes() {
+                        owned.push((dest, copied.clone(), *motion));
+                    }
+                }
+                Err(PasteError::ClipboardEmpty | PasteError::NoSeats | PasteError::NoMimeType) => {}
+                Err(err) => return Err(err.into()),
+            }
         }
+        Ok(owned)
+    }
+
+    // Pick an offered MIME type: Vim's, which has the motion type, or the most preferred one,
+    // if any are configured
+    fn preferred_mime(&self, ty: PasteClipboardType) -> Result<Option<String>> {
+        let offered = match get_mime_types(ty, Seat::Unspecified) {
+            Ok(offered) => offered,
+            Err(PasteError::ClipboardEmpty | PasteError::NoSeats | PasteError::NoMimeType) => {
+                return Ok(None);
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if offered.contains(vim::VIMENC_TEXT) {
+            return Ok(Some(vim::VIMENC_TEXT.into()));
+        }
+        Ok(self
+            .mime_priority
+            .iter()
+            .find(|mime| offered.contains(*mime))
+            .cloned())
     }
 
     fn copy_type(&self, dest: Dest) -> CopyClipboardType {
@@ -59,6 +185,22 @@ impl WaylandBackend {
         }
     }
 
+    // Copy and serve the data in the foreground until all offers are replaced
+    fn hold(
+        &mut self,
+        dest: Dest,
+        data: &str,
+        motion: Option<Motion>,
+        ready: impl FnOnce(),
+    ) -> Result<()> {
+        let mut opts = Options::new();
+        opts.clipboard(self.copy_type(dest)).foreground(true);
+        let copy = opts.prepare_copy_multi(mime_sources(data, motion))?;
+        ready();
+        copy.serve()?;
+        Ok(())
+    }
+
     fn paste_type(&self, source: Source) -> PasteClipboardType {
         match (source, self.primary_supported) {
             (Source::Default | Source::Clipboard, _) => PasteClipboardType::Regular,
@@ -69,29 +211,93 @@ impl WaylandBackend {
     }
 }
 
+// Offers of copied data: Vim's MIME types, which have the motion type, and plain text
+fn mime_sources(data: &str, motion: Option<Motion>) -> Vec<MimeSource> {
+    vec![
+        MimeSource {
+            source: CopySource::Bytes(vim::vimenc_text(data, motion).into()),
+            mime_type: CopyMimeType::Specific(vim::VIMENC_TEXT.into()),
+        },
+        MimeSource {
+            source: CopySource::Bytes(vim::vim_text(data, motion).into()),
+            mime_type: CopyMimeType::Specific(vim::VIM_TEXT.into()),
+        },
+        MimeSource {
+            source: CopySource::Bytes(data.as_bytes().into()),
+            mime_type: CopyMimeType::Text,
+        },
+    ]
+}
+
 impl clipboard::Backend for WaylandBackend {
-    fn copy(&mut self, dest: Dest, data: &str) -> Result<()> {
+    fn copy(&mut self, dest: Dest, data: &str, motion: Option<Motion>) -> Result<()> {
+        let ty = self.copy_type(dest);
+        if let Some(ref server) = self.server {
+            if matches!(ty, CopyClipboardType::Regular | CopyClipboardType::Both) {
+                server.copy(Selection::Regular, data, motion)?;
+            }
+            if matches!(ty, CopyClipboardType::Primary | CopyClipboardType::Both) {
+                server.copy(Selection::Primary, data, motion)?;
+            }
+            return Ok(());
+        }
         let mut opts = Options::new();
-        opts.clipboard(self.copy_type(dest));
-        opts.copy(
-            CopySource::Bytes(data.as_bytes().into()),
-            CopyMimeType::Text,
-        )?;
+        opts.clipboard(ty);
+        opts.copy_multi(mime_sources(data, motion))?;
+        if matches!(ty, CopyClipboardType::Regular | CopyClipboardType::Both) {
+            self.regular = Some((data.into(), motion));
+        }
+        if matches!(ty, CopyClipboardType::Primary | CopyClipboardType::Both) {
+            self.primary = Some((data.into(), motion));
+        }
         Ok(())
     }
 
     fn paste(&mut self, src: Source) -> Result<Data> {
+        let ty = self.paste_type(src);
+        // Answer from memory while we own the selection
+        let selection = match ty {
+            PasteClipboardType::Primary => Selection::Primary,
+            _ => Selection::Regular,
+        };
+        if let Some((data, motion)) = self.server.as_ref().and_then(|s| s.owned(selection)) {
+            let mime = self
+                .mime_priority
+                .iter()
+                .map(String::as_str)
+                .find(|mime| wayland::TEXT_MIME_TYPES.contains(mime))
+                .unwrap_or(wayland::TEXT_MIME_TYPES[0]);
+            return Ok(Data {
+                data: data.to_string(),
+                mime: Some(mime.into()),
+                motion,
+            });
+        }
+        let preferred = self.preferred_mime(ty)?;
+        let mime = match preferred {
+            Some(ref mime) => PasteMimeType::Specific(mime),
+            None => PasteMimeType::TextWithPriority("text/plain"),
+        };
         Ok(
-            match get_contents(
-                self.paste_type(src),
-                Seat::Unspecified,
-                // FIXME: this is not flexible enough, need to inspect offer types manually
-                PasteMimeType::TextWithPriority("text/plain"),
-            ) {
-                Ok((mut pipe, mime)) => {
+            match get_contents(ty, Seat::Unspecified, mime) {
+                Ok((pipe, mime)) => {
+                    // Read just enough to tell if the paste is too large, with room for the
+                    // header of Vim's format
+                    let vim = mime == vim::VIMENC_TEXT;
+                    let limit = self.max_paste.map_or(u64::MAX, |max| {
+                        (max + 1 + VIM_HEADER_SIZE * vim as usize) as u64
+                    });
                     let mut contents = vec![];
-                    pipe.read_to_end(&mut contents)?;
+                    pipe.take(limit).read_to_end(&mut contents)?;
 
+                    if vim {
+                        let (data, motion) = vim::from_vimenc_text(contents);
+                        return Ok(Data {
+                            data,
+                            mime: Some(wayland::TEXT_MIME_TYPES[0].into()),
+                            motion,
+                        });
+                    }
                     let mime = if mime.starts_with("text/_moz") {
                         // HACK: ignore weird internal types from Firefox
                         contents.clear();
@@ -101,14 +307,16 @@ impl clipboard::Backend for WaylandBackend {
                     };
 
                     Data {
-                        data: String::from_utf8_lossy(&contents).into(),
+                        data: clipboard::text(contents),
                         mime,
+                        motion: None,
                     }
                 }
                 Err(PasteError::ClipboardEmpty | PasteError::NoSeats | PasteError::NoMimeType) => {
                     Data {
                         data: "".into(),
                         mime: None,
+                        motion: None,
                     }
                 }
                 Err(err) => return Err(err.into()),
@@ -118,23 +326,121 @@ impl clipboard::Backend for WaylandBackend {
 }
 
 pub struct X11Backend {
+    // Used for pasting; copies are served by our own server
     backend: X11Clipboard,
+    server: x11::Server,
     // Cached here to allow using a slice to represent Dest::Both
     both: [Atom; 2],
+    vimenc_text: Atom,
+    // Timeout waiting for selection owner
+    timeout: Duration,
+    // Maximum paste size, to avoid reading more than that
+    max_paste: Option<usize>,
 }
 
 impl X11Backend {
-    pub fn new() -> Result<X11Backend> {
+    pub fn new(options: &options::Options) -> Result<X11Backend> {
         let backend = X11Clipboard::new()?;
         let primary = backend.setter.atoms.primary;
         let clipboard = backend.setter.atoms.clipboard;
+        let vimenc_text = backend.getter.get_atom(vim::VIMENC_TEXT)?;
 
         Ok(X11Backend {
             backend,
+            server: x11::Server::new()?,
             both: [primary, clipboard],
+            vimenc_text,
+            timeout: options.timeout,
+            max_paste: options.max_paste,
         })
     }
 
+    // Wait until the deadline for an event caused by a request at or after the given sequence
+    // number
+    fn next_event(&self, sequence: u64, deadline: Instant) -> Result<X11Event> {
+        let conn = &self.backend.getter.connection;
+        loop {
+            match conn.poll_for_event_with_sequence()? {
+                Some((event, seq)) if seq >= sequence => return Ok(event),
+                Some(_) => continue,
+                None => (),
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(X11Error::Timeout.into());
+            }
+            let mut fd = libc::pollfd {
+                fd: conn.stream().as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: plain system call with a valid pollfd
+            unsafe { libc::poll(&mut fd, 1, remaining.as_millis().max(1) as i32) };
+        }
+    }
+
+    // Convert a selection to a target and read it, like `X11Clipboard::load` but without reading
+    // more than about `limit` bytes, even from an incremental transfer.  Returns None if the
+    // owner refused the conversion or converted to another type.
+    fn load(&self, selection: Atom, target: Atom, limit: usize) -> Result<Option<Vec<u8>>> {
+        let getter = &self.backend.getter;
+        let conn = &getter.connection;
+        let (window, property) = (getter.window, getter.atoms.property);
+        let deadline = Instant::now() + self.timeout;
+        // Length to read after `read` bytes, in 32-bit units, reaching just past the limit
+        let length = |read: usize| (limit.saturating_sub(read) / 4 + 1).min(u32::MAX as usize);
+
+        // Clear anything left over from an abandoned transfer
+        conn.delete_property(window, property)?;
+        let sequence = conn
+            .convert_selection(window, selection, target, property, x11rb::CURRENT_TIME)?
+            .sequence_number();
+        conn.flush()?;
+        let notify = loop {
+            match self.next_event(sequence, deadline)? {
+                X11Event::SelectionNotify(event) if event.selection == selection => break event,
+                _ => (),
+            }
+        };
+        if notify.property == x11rb::NONE {
+            return Ok(None);
+        }
+        let reply = conn
+            .get_property(false, window, property, AtomEnum::ANY, 0, length(0) as u32)?
+            .reply()?;
+        conn.delete_property(window, property)?;
+        conn.flush()?;
+        if reply.type_ != getter.atoms.incr {
+            return Ok((reply.type_ == target).then_some(reply.value));
+        }
+
+        // Incremental transfer: the owner sets each chunk once we've deleted the last one, and
+        // ends with an empty one
+        let mut data = vec![];
+        while data.len() <= limit {
+            match self.next_event(sequence, deadline)? {
+                X11Event::PropertyNotify(event)
+                    if event.atom == property && event.state == Property::NEW_VALUE =>
+                {
+                    let length = length(data.len()) as u32;
+                    let reply = conn
+                        .get_property(true, window, property, AtomEnum::ANY, 0, length)?
+                        .reply()?;
+                    if reply.value.is_empty() {
+                        break;
+                    }
+                    if reply.type_ != target {
+                        return Ok(None);
+                    }
+                    data.extend_from_slice(&reply.value);
+                    conn.flush()?;
+                }
+                _ => (),
+            }
+        }
+        Ok(Some(data))
+    }
+
     fn source_atom(&self, source: Source) -> Atom {
         match source {
             Source::Default | Source::Primary => self.backend.setter.atoms.primary,
@@ -151,30 +457,140 @@ impl X11Backend {
             Dest::Both => &self.both,
         }
     }
+
+    // Is our server's window the current owner of the selection?
+    fn owns(&self, atom: Atom) -> Result<bool> {
+        let getter = &self.backend.getter;
+        let reply = getter.connection.get_selection_owner(atom)?.reply()?;
+        Ok(reply.owner == self.server.window)
+    }
+
+    // Data we copied to the selection, if we still own it.  The server only hears that another
+    // client took the selection after the fact, so check.
+    fn cached(&self, atom: Atom) -> Result<Option<(Arc<str>, Option<Motion>)>> {
+        match self.server.owned(atom) {
+            Some(offer) if self.owns(atom)? => Ok(Some(offer)),
+            _ => Ok(None),
+        }
+    }
+
+    fn owned(&mut self) -> Result<Vec<(Dest, String, Option<Motion>)>> {
+        let [primary, clipboard] = self.both;
+        let mut owned = vec![];
+        for (dest, atom) in [(Dest::Clipboard, clipboard), (Dest::Primary, primary)] {
+            if let Some((data, motion)) = self.cached(atom)? {
+                owned.push((dest, data.to_string(), motion));
+            }
+        }
+        Ok(owned)
+    }
+
+    // Ask the clipboard manager to save the clipboard contents (the SAVE_TARGETS protocol from
+    // the freedesktop.org clipboard manager specification)
+    fn handoff(&mut self) -> Result<bool> {
+        let getter = &self.backend.getter;
+        let manager = getter.get_atom("CLIPBOARD_MANAGER")?;
+        let save_targets = getter.get_atom("SAVE_TARGETS")?;
+        if !self.owns(self.backend.setter.atoms.clipboard)?
+            || getter
+                .connection
+                .get_selection_owner(manager)?
+                .reply()?
+                .owner
+                == x11rb::NONE
+        {
+            return Ok(false);
+        }
+
+        // The manager requests the contents from the server's window, which owns the selection
+        // and keeps serving them from its own thread while we wait for the manager to finish
+        getter.connection.convert_selection(
+            getter.window,
+            manager,
+            save_targets,
+            getter.atoms.property,
+            x11rb::CURRENT_TIME,
+        )?;
+        getter.connection.flush()?;
+        let deadline = Instant::now() + HANDOFF_TIMEOUT;
+        while Instant::now() < deadline {
+            match getter.connection.poll_for_event()? {
+                Some(X11Event::SelectionNotify(event)) if event.selection == manager => {
+                    return Ok(event.property != x11rb::NONE);
+                }
+                Some(_) => (),
+                None => thread::sleep(HANDOFF_POLL_INTERVAL),
+            }
+        }
+        Ok(false)
+    }
+
+    // Copy and serve the data until other clients take ownership of all destination selections
+    fn hold(
+        &mut self,
+        dest: Dest,
+        data: &str,
+        motion: Option<Motion>,
+        ready: impl FnOnce(),
+    ) -> Result<()> {
+        let atoms = self.dest_atoms(dest).to_vec();
+        for atom in &atoms {
+            self.server.copy(*atom, data, motion)?;
+        }
+        ready();
+        loop {
+            thread::sleep(HOLD_POLL_INTERVAL);
+            let mut owned = false;
+            for atom in &atoms {
+                owned |= self.owns(*atom)?;
+            }
+            if !owned {
+                return Ok(());
+            }
+        }
+    }
 }
 
 impl clipboard::Backend for X11Backend {
-    fn copy(&mut self, dest: Dest, data: &str) -> Result<()> {
+    fn copy(&mut self, dest: Dest, data: &str, motion: Option<Motion>) -> Result<()> {
         for atom in self.dest_atoms(dest) {
-            self.backend.store(
-                *atom,
-                self.backend.setter.atoms.utf8_string,
-                data.as_bytes(),
-            )?;
+            self.server.copy(*atom, data, motion)?;
         }
         Ok(())
     }
 
     fn paste(&mut self, source: Source) -> Result<Data> {
-        let contents = self.backend.load(
-            self.source_atom(source),
-            self.backend.setter.atoms.utf8_string,
-            self.backend.setter.atoms.property,
-            Duration::from_millis(100),
-        )?;
+        let atom = self.source_atom(source);
+        // Checking ownership is a single round trip, rather than a conversion through the owner
+        // bounded by the timeout
+        if let Some((data, motion)) = self.cached(atom)? {
+            return Ok(Data {
+                data: data.to_string(),
+                mime: None,
+                motion,
+            });
+        }
+        // Read just enough to tell if the paste is too large
+        let limit = self.max_paste.map_or(usize::MAX, |max| max + 1);
+        // Vim's target has the motion type.  Owners which don't offer it refuse the conversion,
+        // or with x11-clipboard send UTF8_STRING anyway.
+        let vim_limit = limit.saturating_add(VIM_HEADER_SIZE);
+        if let Some(contents) = self.load(atom, self.vimenc_text, vim_limit)?
+            && !contents.is_empty()
+        {
+            let (data, motion) = vim::from_vimenc_text(contents);
+            return Ok(Data {
+                data,
+                mime: None,
+                motion,
+            });
+        }
+        let utf8_string = self.backend.setter.atoms.utf8_string;
+        let contents = self.load(atom, utf8_string, limit)?.unwrap_or_default();
         Ok(Data {
-            data: String::from_utf8_lossy(&contents).into(),
+            data: clipboard::text(contents),
             mime: None,
+            motion: None,
         })
     }
 }
@@ -185,10 +601,10 @@ pub enum Backend {
 }
 
 impl clipboard::Backend for Backend {
-    fn copy(&mut self, dest: Dest, data: &str) -> Result<()> {
+    fn copy(&mut self, dest: Dest, data: &str, motion: Option<Motion>) -> Result<()> {
         match *self {
-            Backend::Wayland(ref mut wl) => wl.copy(dest, data),
-            Backend::X11(ref mut x11) => x11.copy(dest, data),
+            Backend::Wayland(ref mut wl) => wl.copy(dest, data, motion),
+            Backend::X11(ref mut x11) => x11.copy(dest, data, motion),
         }
     }
 
@@ -198,6 +614,34 @@ impl clipboard::Backend for Backend {
             Backend::X11(ref mut x11) => x11.paste(src),
         }
     }
+
+    fn configure(&mut self, options: &options::Options) {
+        match *self {
+            Backend::Wayland(ref mut wl) => {
+                wl.mime_priority = options.mime_priority.clone();
+                wl.max_paste = options.max_paste;
+            }
+            Backend::X11(ref mut x11) => {
+                x11.timeout = options.timeout;
+                x11.max_paste = options.max_paste;
+            }
+        }
+    }
+
+    fn handoff(&mut self) -> Result<bool> {
+        match *self {
+            // Wayland has no clipboard manager protocol
+            Backend::Wayland(_) => Ok(false),
+            Backend::X11(ref mut x11) => x11.handoff(),
+        }
+    }
+
+    fn owned(&mut self) -> Result<Vec<(Dest, String, Option<Motion>)>> {
+        match *self {
+            Backend::Wayland(ref mut wl) => wl.owned(),
+            Backend::X11(ref mut x11) => x11.owned(),
+        }
+    }
 }
 
 fn have_env_var(var: &str) -> bool {
@@ -208,13 +652,70 @@ fn have_env_var(var: &str) -> bool {
 }
 
 impl Backend {
-    pub fn new() -> Result<Backend> {
-        Ok(if have_env_var("WAYLAND_DISPLAY") {
-            Backend::Wayland(WaylandBackend::new())
-        } else if have_env_var("DISPLAY") {
-            Backend::X11(X11Backend::new()?.into())
-        } else {
-            return Err(Error::new(ErrorDetail::NoDisplayServer));
+    fn with_kind(kind: BackendKind, options: &options::Options) -> Result<Backend> {
+        Ok(match kind {
+            BackendKind::Wayland => Backend::Wayland(WaylandBackend::new(options)?),
+            BackendKind::X11 => Backend::X11(X11Backend::new(options)?.into()),
         })
     }
+
+    // Create first working backend in the configured chain, or based on the environment
+    pub fn new(options: &options::Options) -> Result<Backend> {
+        let chain = if options.backends.is_empty() {
+            let mut chain = vec![];
+            if have_env_var("WAYLAND_DISPLAY") {
+                chain.push(BackendKind::Wayland);
+            }
+            if have_env_var("DISPLAY") {
+                chain.push(BackendKind::X11);
+            }
+            chain
+        } else {
+            options.backends.clone()
+        };
+
+        let mut error = Error::new(ErrorDetail::NoDisplayServer);
+        for kind in chain {
+            match Self::with_kind(kind, options) {
+                Ok(backend) => {
+                    let mut fields = serde_json::Map::new();
+                    fields.insert("backend".into(), kind.name().into());
+                    log::log(Level::Info, "selected backend", fields);
+                    return Ok(backend);
+                }
+                Err(err) => {
+                    log::error(
+                        Level::Warn,
+                        &format!("{} backend unavailable", kind.name()),
+                        &err,
+                    );
+                    error = err;
+                }
+            }
+        }
+        Err(error)
+    }
+
+    pub fn kind(&self) -> BackendKind {
+        match *self {
+            Backend::Wayland(_) => BackendKind::Wayland,
+            Backend::X11(_) => BackendKind::X11,
+        }
+    }
+
+    // Copy to clipboard and keep serving it until another client takes ownership.  Used when
+    // there is no long-lived process around to serve the selection, e.g. when acting as `xclip`.
+    // `ready` is called once the selection has been set.
+    pub fn hold(
+        &mut self,
+        dest: Dest,
+        data: &str,
+        motion: Option<Motion>,
+        ready: impl FnOnce(),
+    ) -> Result<()> {
+        match *self {
+            Backend::Wayland(ref mut wl) => wl.hold(dest, data, motion, ready),
+            Backend::X11(ref mut x11) => x11.hold(dest, data, motion, ready),
+        }
+    }
 }
@@ -0,0 +1,199 @@
+// Leveled logging, as JSON lines to a log file or as plain text to stderr
+use serde_json::{Map, Value};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::options::Options;
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn parse(name: &str) -> Option<Level> {
+        Some(match name {
+            "error" => Level::Error,
+            "warn" => Level::Warn,
+            "info" => Level::Info,
+            "debug" => Level::Debug,
+            "trace" => Level::Trace,
+            _ => return None,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Level::Error => "Error",
+            Level::Warn => "Warning",
+            Level::Info => "Info",
+            Level::Debug => "Debug",
+            Level::Trace => "Trace",
+        }
+    }
+}
+
+struct Logger {
+    level: Level,
+    // Log file, or stderr if none
+    file: Option<File>,
+    // Log clipboard contents instead of redacting them
+    contents: bool,
+}
+
+// Until initialized, errors go to stderr
+static LOGGER: Mutex<Logger> = Mutex::new(Logger {
+    level: Level::Error,
+    file: None,
+    contents: false,
+});
+
+fn logger() -> MutexGuard<'static, Logger> {
+    // Keep logging even if a panic happened while holding the lock
+    LOGGER.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// (Re)configure logging from options
+pub fn init(options: &Options) -> io::Result<()> {
+    let file = match options.log_file {
+        Some(ref path) => Some(File::options().create(true).append(true).open(path)?),
+        None => None,
+    };
+    // Default to more detail in a log file, since it doesn't get in anyone's way
+    let level = options.log_level.unwrap_or(match file {
+        Some(_) => Level::Info,
+        None => Level::Error,
+    });
+    *logger() = Logger {
+        level,
+        file,
+        contents: options.log_contents,
+    };
+    Ok(())
+}
+
+pub fn enabled(level: Level) -> bool {
+    level <= logger().level
+}
+
+// Convert error to JSON, capturing source chain
+pub fn error_to_json<E: Error + ?Sized>(error: &E, map: &mut Map<String, Value>) {
+    map.insert("message".into(), error.to_string().into());
+    if let Some(source) = error.source() {
+        let mut sub = Map::new();
+        error_to_json(&source, &mut sub);
+        map.insert("source".into(), sub.into());
+    }
+}
+
+// Render error and its sources on one line
+fn error_chain(error: &Value) -> String {
+    let mut chain = vec![];
+    let mut error = Some(error);
+    while let Some(e) = error {
+        chain.extend(e.get("message").and_then(Value::as_str));
+        error = e.get("source");
+    }
+    chain.join(": ")
+}
+
+fn timestamp() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as f64
+        / 1000.0
+}
+
+// Write a log record with structured fields.  An `error` field is rendered as a source chain
+// on stderr.
+pub fn log(level: Level, message: &str, fields: Map<String, Value>) {
+    let mut logger = logger();
+    if level > logger.level {
+        return;
+    }
+    // Logging failures are deliberately ignored; there's nowhere to report them
+    let _ = match logger.file {
+        Some(ref mut file) => {
+            let mut record = Map::new();
+            record.insert("time".into(), timestamp().into());
+            record.insert("level".into(), level.name().into());
+            record.insert("message".into(), message.into());
+            record.extend(fields);
+            writeln!(file, "{}", Value::Object(record))
+        }
+        None => {
+            let mut line = format!("{}: {}", level.label(), message);
+            for (key, value) in &fields {
+                match key.as_ref() {
+                    "error" => line = format!("{}: {}", line, error_chain(value)),
+                    _ => line = format!("{} {}={}", line, key, value),
+                }
+            }
+            writeln!(io::stderr(), "{}", line)
+        }
+    };
+}
+
+// Flush log records before exiting
+pub fn flush() {
+    let _ = match logger().file {
+        Some(ref mut file) => file.flush().and_then(|_| file.sync_data()),
+        None => io::stderr().flush(),
+    };
+}
+
+// Log an error with its source chain
+pub fn error<E: Error + ?Sized>(level: Level, message: &str, error: &E) {
+    let mut err = Map::new();
+    error_to_json(error, &mut err);
+    let mut fields = Map::new();
+    fields.insert("error".into(), err.into());
+    log(level, message, fields);
+}
+
+// Replace clipboard contents in a request or response with their size, unless configured to
+// log contents.  Batches nest requests and results, so the contents may be at any depth.
+pub fn redact(value: &Value) -> Value {
+    if logger().contents {
+        return value.clone();
+    }
+    fn size(value: &Value) -> Value {
+        match value {
+            Value::String(s) => format!("<{} bytes>", s.len()).into(),
+            Value::Array(a) => a.iter().map(size).collect(),
+            value => value.clone(),
+        }
+    }
+    fn contents(value: &Value) -> Value {
+        match value {
+            Value::Object(obj) => obj
+                .iter()
+                .map(|(key, value)| match key.as_ref() {
+                    "data" | "lines" => (key.clone(), size(value)),
+                    _ => (key.clone(), contents(value)),
+                })
+                .collect(),
+            Value::Array(a) => a.iter().map(contents).collect(),
+            value => value.clone(),
+        }
+    }
+    contents(value)
+}
@@ -1,11 +1,35 @@
+use serde::Deserialize;
 use serde_json::{Map, Value};
+use std::borrow::Cow;
 use std::env;
 use std::error::Error;
-use std::io::{self, BufRead, Write};
+use std::io::{self, Write};
+use std::panic;
+use std::process::ExitCode;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Instant;
 
+mod bench;
 mod clipboard;
+mod compat;
+mod config;
+mod doctor;
+mod log;
+mod msgpack;
+mod options;
+mod protocol;
+mod record;
+mod rpc;
+mod spill;
+mod wire;
 
-use clipboard::{Backend, Data, Dest, Source};
+use clipboard::{Backend, Data, Dest, Motion, PayloadTooLarge};
+use compat::Personality;
+use log::Level;
+use options::{BackendKind, Command, Options, Oversized, UsageError};
+use protocol::{ErrorInfo, Request, Response};
+use wire::{Message, Wire};
 
 #[cfg(target_os = "windows")]
 mod windows;
@@ -15,6 +39,12 @@ use windows as backend;
 #[cfg(target_os = "linux")]
 mod linux;
 #[cfg(target_os = "linux")]
+mod vim;
+#[cfg(target_os = "linux")]
+mod wayland;
+#[cfg(target_os = "linux")]
+mod x11;
+#[cfg(target_os = "linux")]
 use linux as backend;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -22,152 +52,721 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 // FIXME: maybe use a specialized error type for some of this file
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
-// Clipboard action representation
-enum Action<'a> {
-    Copy(Dest, &'a str),
-    Paste(Source),
-    Query,
-}
-
-// Parsing from JSON
-impl<'a> Action<'a> {
-    fn source(name: Option<&Value>) -> Result<Source> {
-        Ok(match name {
-            None => Source::Default,
-            Some(Value::String(name)) => match name.as_ref() {
-                "default" => Source::Default,
-                "clipboard" => Source::Clipboard,
-                "primary" => Source::Primary,
-                _ => return Err(format!("Invalid clipboard source: {}", name).into()),
-            },
-            Some(value) => return Err(format!("Invalid clipboard source: {}", value).into()),
-        })
-    }
+// Appended to pastes truncated to the maximum size
+const TRUNCATION_MARKER: &str = "\n[truncated by clipipe]";
 
-    fn dest(name: Option<&Value>) -> Result<Dest> {
-        Ok(match name {
-            None => Dest::Default,
-            Some(Value::String(name)) => match name.as_ref() {
-                "default" => Dest::Default,
-                "clipboard" => Dest::Clipboard,
-                "primary" => Dest::Primary,
-                "both" => Dest::Both,
-                _ => return Err(format!("Invalid clipboard destination: {}", name).into()),
-            },
-            Some(value) => return Err(format!("Invalid clipboard destination: {}", value).into()),
-        })
-    }
+// Exit status by class of fatal error
+const EXIT_FAILURE: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_CONFIG: u8 = 3;
+const EXIT_BACKEND: u8 = 4;
+const EXIT_IO: u8 = 5;
+// Same as the Rust runtime uses
+const EXIT_PANIC: u8 = 101;
 
-    fn data(data: Option<&Value>) -> Result<&str> {
-        Ok(match data {
-            None => return Err("Request is missing `data`".into()),
-            Some(Value::String(data)) => data.as_ref(),
-            Some(value) => return Err(format!("Invalid clipboard data: {}", value).into()),
-        })
-    }
+struct Clipipe {
+    backend: Box<dyn Backend>,
+    // Kind of the real backend in use, if any
+    kind: Option<BackendKind>,
+    options: Options,
+    // Copy held back to be combined with later ones
+    pending: Option<Pending>,
+    // Exit after the current request
+    exiting: bool,
+}
 
-    pub fn parse(doc: &'a Map<String, Value>) -> Result<Action<'a>> {
-        Ok(match doc.get("action") {
-            None => return Err("No action specified".into()),
-            Some(Value::String(name)) => match name.as_ref() {
-                "copy" => Action::Copy(
-                    Self::dest(doc.get("clipboard"))?,
-                    Self::data(doc.get("data"))?,
-                ),
-                "paste" => Action::Paste(Self::source(doc.get("clipboard"))?),
-                "query" => Action::Query,
-                name => return Err(format!("Invalid action: {}", name).into()),
-            },
-            Some(value) => return Err(format!("Expected string for action: {}", value).into()),
-        })
+// Coalesced copy, made when its window ends or before anything that depends on it
+struct Pending {
+    dest: Dest,
+    data: String,
+    motion: Option<Motion>,
+    deadline: Instant,
+}
+
+// Token identifying pasted contents, so clients polling the clipboard can skip unchanged data.
+// The hash is 64-bit FNV-1a, which unlike `DefaultHasher` is fixed, so tokens in recordings
+// still match after a toolchain upgrade.
+fn change_token(data: &str, mime: Option<&str>) -> String {
+    // 0xff never occurs in UTF-8, so it separates the MIME type from the data
+    let mime = mime
+        .into_iter()
+        .flat_map(|mime| [0xff].into_iter().chain(mime.bytes()));
+    let hash = data
+        .bytes()
+        .chain(mime)
+        .fold(0xcbf29ce484222325, |hash: u64, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+    format!("{:016x}", hash)
+}
+
+// Text of a Neovim register given as lines, which for a linewise register end with an empty
+// line
+fn join_lines(lines: &[Cow<str>], motion: Option<Motion>) -> String {
+    end_lines(lines.join("\n").into(), motion).into_owned()
+}
+
+// Text of a register with its lines already joined.  Linewise text always ends with a newline.
+fn end_lines(mut data: Cow<str>, motion: Option<Motion>) -> Cow<str> {
+    if motion == Some(Motion::Line) && !data.ends_with('\n') {
+        data.to_mut().push('\n');
     }
+    data
 }
 
-struct Clipipe {
-    backend: backend::Backend,
+// Lines of pasted text and the register type, if the backend didn't know it: linewise if it
+// ends with a newline
+fn split_lines(data: &str, motion: Option<Motion>) -> (std::str::Split<'_, char>, &'static str) {
+    let motion = motion.unwrap_or(match data.ends_with('\n') {
+        true => Motion::Line,
+        false => Motion::Char,
+    });
+    (data.split('\n'), motion.regtype())
 }
 
 impl Clipipe {
     // Query version number
-    fn query() -> Map<String, Value> {
-        let mut res = Map::new();
-        res.insert("version".into(), VERSION.into());
-        res
+    fn query() -> Response<'static> {
+        Response {
+            version: Some(VERSION.into()),
+            ..Response::ok()
+        }
     }
 
-    // Process request object, return response object
-    fn request(&mut self, obj: Map<String, Value>) -> Result<Map<String, Value>> {
-        Ok(match Action::parse(&obj)? {
-            Action::Query => Self::query(),
-            Action::Copy(dest, data) => {
-                self.backend.copy(dest, data)?;
-                Map::new()
-            }
-            Action::Paste(source) => {
-                let Data { data, mime } = self.backend.paste(source)?;
-                let mut res = Map::new();
-                res.insert("data".into(), data.into());
-                if let Some(mime) = mime {
-                    res.insert("mime".into(), mime.into());
+    // Process request, return response
+    fn request(&mut self, req: &Request) -> Result<Response<'static>> {
+        Ok(match *req {
+            Request::Query => Self::query(),
+            Request::Copy {
+                clipboard,
+                ref data,
+                ref file,
+                ref lines,
+                ref regtype,
+            } => {
+                let motion = regtype.as_deref().and_then(Motion::from_regtype);
+                let data = match (data, file, lines) {
+                    (Some(data), None, None) => end_lines(Cow::Borrowed(data.as_ref()), motion),
+                    (None, Some(path), None) => {
+                        let data = spill::take(path, self.options.max_request)?;
+                        end_lines(data.into(), motion)
+                    }
+                    (None, None, Some(lines)) => Cow::Owned(join_lines(lines, motion)),
+                    (None, None, None) => return Err("Request is missing `data`".into()),
+                    _ => return Err("Only one of `data`, `file` and `lines` is allowed".into()),
+                };
+                if self.options.coalesce.is_zero() {
+                    self.backend.copy(clipboard, &data, motion)?;
+                } else {
+                    self.defer(clipboard, data.into_owned(), motion);
                 }
+                Response::ok()
+            }
+            Request::Paste {
+                clipboard,
+                ref if_changed_since,
+                lines,
+            } => {
+                self.flush();
+                let Data { data, mime, motion } = self.backend.paste(clipboard)?;
+                let (data, truncated) = self.limit(data)?;
+                let token = change_token(&data, mime.as_deref());
+                let mut res = Response {
+                    truncated,
+                    ..Response::ok()
+                };
+                if if_changed_since.as_deref() == Some(token.as_str()) {
+                    res.unchanged = true;
+                } else {
+                    let (split, regtype) = split_lines(&data, motion);
+                    if lines {
+                        res.regtype = Some(regtype.into());
+                    }
+                    match self.options.spill.filter(|spill| data.len() > *spill) {
+                        // Too large to send efficiently through the pipe
+                        Some(_) => {
+                            res.file = Some(spill::write(&data)?.to_string_lossy().into());
+                            res.size = Some(data.len());
+                        }
+                        None if lines => res.lines = Some(split.map(Into::into).collect()),
+                        None => res.data = Some(data.into()),
+                    }
+                    res.mime = mime;
+                }
+                res.token = Some(token);
                 res
             }
+            Request::Reload => self.reload()?,
+            Request::Shutdown { handoff } => self.shutdown(handoff.unwrap_or(self.options.handoff)),
+            Request::Schema => Response {
+                schema: Some(protocol::schema()),
+                ..Response::ok()
+            },
+            Request::Batch { ref requests } => {
+                if requests
+                    .iter()
+                    .any(|req| matches!(req, Request::Batch { .. }))
+                {
+                    return Err("Batch requests can't be nested".into());
+                }
+                self.backend.lock()?;
+                let results = requests
+                    .iter()
+                    .map(|req| Self::response(self.request(req)))
+                    .collect();
+                // Coalesced copies still happen under the lock
+                self.flush();
+                self.backend.unlock();
+                Response {
+                    results: Some(results),
+                    ..Response::ok()
+                }
+            }
         })
     }
 
-    fn new() -> Result<Clipipe> {
-        Ok(Clipipe {
-            backend: backend::Backend::new()?,
+    // Re-read configuration, keeping the current backend (and any selection it owns)
+    fn reload(&mut self) -> Result<Response<'static>> {
+        let options = self.options.reload()?;
+        log::init(&options)?;
+        wire::set_max_request(&options);
+        self.backend.configure(&options);
+
+        // Switching backends requires a restart
+        let preferred = options.backends.first();
+        let restart_required = self
+            .kind
+            .is_some_and(|kind| preferred.is_some_and(|p| *p != kind));
+        self.options = options;
+        let mut fields = Map::new();
+        fields.insert("restart_required".into(), restart_required.into());
+        log::log(Level::Info, "reloaded configuration", fields);
+        Ok(Response {
+            restart_required: Some(restart_required),
+            ..Response::ok()
         })
     }
+
+    // Apply the maximum paste size.  Returns the data and whether it was truncated.
+    fn limit(&self, mut data: String) -> Result<(String, bool)> {
+        let Some(max) = self.options.max_paste.filter(|max| data.len() > *max) else {
+            return Ok((data, false));
+        };
+        match self.options.oversized {
+            // The backend may have stopped reading early, so the size isn't known
+            Oversized::Reject => Err(PayloadTooLarge { size: None, max }.into()),
+            Oversized::Truncate => {
+                data.truncate(data.floor_char_boundary(max));
+                data.push_str(TRUNCATION_MARKER);
+                Ok((data, true))
+            }
+        }
+    }
+
+    // Hold back a copy, replacing a pending one to the same destination
+    fn defer(&mut self, dest: Dest, data: String, motion: Option<Motion>) {
+        let deadline = match self.pending.take() {
+            Some(pending) if pending.dest == dest => pending.deadline,
+            pending => {
+                self.pending = pending;
+                self.flush();
+                Instant::now() + self.options.coalesce
+            }
+        };
+        self.pending = Some(Pending {
+            dest,
+            data,
+            motion,
+            deadline,
+        });
+    }
+
+    // Make the pending copy, if any.  Its request has already succeeded, so errors can only be
+    // logged.
+    fn flush(&mut self) {
+        if let Some(Pending {
+            dest, data, motion, ..
+        }) = self.pending.take()
+            && let Err(err) = self.backend.copy(dest, &data, motion)
+        {
+            log::error(Level::Warn, "coalesced copy failed", &err);
+        }
+    }
+
+    // Prepare to exit, handing off the clipboard if requested
+    fn shutdown(&mut self, handoff: bool) -> Response<'static> {
+        self.flush();
+        self.exiting = true;
+        Response {
+            shutdown: true,
+            handoff: handoff.then(|| {
+                self.backend.handoff().unwrap_or_else(|err| {
+                    log::error(Level::Warn, "clipboard handoff failed", &err);
+                    false
+                })
+            }),
+            ..Response::ok()
+        }
+    }
+
+    // Keep selections we own available after exiting, by handing the clipboard to a clipboard
+    // manager if possible and otherwise serving each from a `clipipe hold` process
+    fn persist(&mut self) {
+        let owned = match self.backend.owned() {
+            Ok(owned) => owned,
+            Err(err) => return log::error(Level::Warn, "couldn't check selection", &err),
+        };
+        if owned.is_empty() {
+            return;
+        }
+        let handed_off = owned
+            .iter()
+            .any(|(dest, ..)| matches!(dest, Dest::Clipboard))
+            && self.backend.handoff().unwrap_or_else(|err| {
+                log::error(Level::Warn, "clipboard handoff failed", &err);
+                false
+            });
+        for (dest, data, motion) in owned {
+            if handed_off && matches!(dest, Dest::Clipboard) {
+                continue;
+            }
+            let name = dest.name();
+            match compat::spawn_hold(&self.options, dest, &data, motion) {
+                Ok(()) => {
+                    let mut fields = Map::new();
+                    fields.insert("clipboard".into(), name.into());
+                    log::log(Level::Info, "holding selection", fields);
+                }
+                Err(err) => log::error(Level::Warn, "couldn't hold selection", &*err),
+            }
+        }
+    }
+
+    // Handle request line, return response
+    fn handle(&mut self, line: &str) -> Response<'static> {
+        let start = Instant::now();
+        let req: Result<Request> = self
+            .options
+            .max_request
+            .filter(|max| line.len() > *max)
+            .map_or(Ok(()), |max| {
+                Err(PayloadTooLarge {
+                    size: Some(line.len()),
+                    max,
+                }
+                .into())
+            })
+            .and_then(|_| serde_json::from_str(line).map_err(|e| e.into()));
+        let res = self.process(req, start);
+        record::request(line, &res, start.elapsed());
+        res
+    }
+
+    // Handle decoded MessagePack request, which the reader already checked against the maximum
+    // size
+    fn handle_value(&mut self, value: &Value) -> Response<'static> {
+        let start = Instant::now();
+        let req = Request::deserialize(value).map_err(|e| e.into());
+        let res = self.process(req, start);
+        // Recorded as JSON, so it can be replayed
+        record::request(value, &res, start.elapsed());
+        res
+    }
+
+    // Process parsed request and log it
+    fn process(&mut self, req: Result<Request>, start: Instant) -> Response<'static> {
+        let (req, res) = match req {
+            Ok(req) => {
+                let res = self.request(&req);
+                (Some(req), res)
+            }
+            Err(e) => (None, Err(e)),
+        };
+        let res = Self::response(res);
+        log_request(req.as_ref(), &res, start);
+        res
+    }
+
+    // Answer a request which was too large to read.  It isn't recorded, since there's no line
+    // to replay.
+    fn reject(&mut self, size: usize) -> Response<'static> {
+        let start = Instant::now();
+        let res = Self::response(Err(PayloadTooLarge {
+            size: Some(size),
+            max: self.options.max_request.unwrap_or(usize::MAX),
+        }
+        .into()));
+        log_request(None, &res, start);
+        res
+    }
+
+    // Answer a msgpack-RPC call which couldn't be understood, which isn't recorded either
+    fn invalid(&mut self) -> Response<'static> {
+        let start = Instant::now();
+        let res = Self::response(Err(
+            "Invalid msgpack-RPC call: expected a method name and a map parameter".into(),
+        ));
+        log_request(None, &res, start);
+        res
+    }
+
+    // Convert result to response
+    fn response(res: Result<Response<'static>>) -> Response<'static> {
+        res.unwrap_or_else(|e| Response::error(ErrorInfo::new(&*e)))
+    }
+
+    fn with_backend(
+        options: &Options,
+        backend: Box<dyn Backend>,
+        kind: Option<BackendKind>,
+    ) -> Clipipe {
+        Clipipe {
+            backend,
+            kind,
+            options: options.clone(),
+            pending: None,
+            exiting: false,
+        }
+    }
+
+    fn new(options: &Options) -> Result<Clipipe> {
+        let backend = backend::Backend::new(options)?;
+        let kind = backend.kind();
+        let backend: Box<dyn Backend> = match options.record {
+            Some(ref path) => {
+                record::start(path, kind)?;
+                Box::new(record::Recorder::new(backend))
+            }
+            None => Box::new(backend),
+        };
+        Ok(Self::with_backend(options, backend, Some(kind)))
+    }
 }
 
-// Convert error to JSON, capturing source chain
-fn error_to_json<E: Error + ?Sized>(error: &E, map: &mut Map<String, Value>) {
-    map.insert("message".into(), error.to_string().into());
-    if let Some(source) = error.source() {
-        let mut sub = Map::new();
-        error_to_json(&source, &mut sub);
-        map.insert("source".into(), sub.into());
+// Log a request and its response
+fn log_request(request: Option<&Request>, response: &Response, start: Instant) {
+    let level = if response.success {
+        Level::Info
+    } else {
+        Level::Warn
+    };
+    if !log::enabled(level) {
+        return;
+    }
+    let mut fields = Map::new();
+    if let Some(request) = request {
+        fields.insert("action".into(), request.action().into());
+    }
+    fields.insert(
+        "elapsed_ms".into(),
+        (start.elapsed().as_secs_f64() * 1000.0).into(),
+    );
+    fields.insert("success".into(), response.success.into());
+    if !response.success
+        && let Ok(Value::Object(mut error)) = serde_json::to_value(response)
+    {
+        error.remove("success");
+        fields.insert("error".into(), error.into());
+    }
+    if log::enabled(Level::Debug) {
+        if let Some(request) = request.and_then(|r| serde_json::to_value(r).ok()) {
+            fields.insert("request".into(), log::redact(&request));
+        }
+        if let Ok(response) = serde_json::to_value(response) {
+            fields.insert("response".into(), log::redact(&response));
+        }
     }
+    log::log(level, "request", fields);
 }
 
-fn run() -> Result<()> {
-    let stdin = io::stdin().lock();
-    let mut stdout = io::stdout().lock();
+fn exit_code(error: &(dyn Error + 'static)) -> u8 {
+    if error.is::<UsageError>() {
+        EXIT_USAGE
+    } else if error.is::<config::Error>() {
+        EXIT_CONFIG
+    } else if error.is::<clipboard::Error>() {
+        EXIT_BACKEND
+    } else if error.is::<io::Error>() {
+        EXIT_IO
+    } else {
+        EXIT_FAILURE
+    }
+}
+
+// Write final response describing why we're exiting, so the client doesn't have to guess from
+// the exit status
+fn write_fatal(res: Response, code: u8, wire: Wire) {
+    let res = Response {
+        success: false,
+        fatal: true,
+        exit_code: Some(code),
+        ..res
+    };
+    // Nothing to be done if the client is gone
+    let _ = wire.write(&mut io::stdout().lock(), &res, None);
+}
 
-    // Quick query path, used to decide if binary is right version
-    if env::args().any(|arg| arg == "--query") {
-        writeln!(stdout, "{}", Value::Object(Clipipe::query()))?;
-        return Ok(());
+// Report fatal error and get exit status.  The error is also written as a response if we speak
+// the protocol.
+fn fatal(error: &(dyn Error + 'static), protocol: Option<Wire>) -> ExitCode {
+    let code = exit_code(error);
+    log::error(Level::Error, "exiting", error);
+    if let Some(wire) = protocol {
+        write_fatal(Response::error(ErrorInfo::new(error)), code, wire);
     }
+    ExitCode::from(code)
+}
 
-    let mut clipipe = Clipipe::new()?;
+// Report panics on the main thread as a fatal error before unwinding
+fn set_panic_hook(wire: Wire) {
+    let default = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if thread::current().name() == Some("main") {
+            let payload = info
+                .payload()
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| info.payload().downcast_ref::<String>().map(|s| s.as_ref()))
+                .unwrap_or("unknown cause");
+            let error = ErrorInfo {
+                message: "panic".into(),
+                location: None,
+                source: Some(Box::new(ErrorInfo {
+                    message: payload.into(),
+                    location: info.location().map(|location| location.to_string()),
+                    source: None,
+                })),
+            };
+            if let Ok(Value::Object(fields)) = serde_json::to_value(&error) {
+                log::log(Level::Error, "exiting", fields);
+            }
+            write_fatal(Response::error(error), EXIT_PANIC, wire);
+        }
+        default(info);
+    }));
+}
 
-    for line in stdin.lines() {
-        let res: Value = match serde_json::from_str(line?.as_ref())
-            .map_err(|e| e.into())
-            .and_then(|obj| clipipe.request(obj))
-        {
-            Ok(mut res) => {
-                // Add success/error discriminator
-                res.insert("success".into(), true.into());
-                res.into()
+// Input to the request loop
+enum Event {
+    // Request, or None at end of input
+    Request(Option<Message>),
+    // Request over the maximum size, which was discarded unread, and its msgpack-RPC message ID
+    Oversized(usize, Option<u64>),
+    // Malformed msgpack-RPC call, and its message ID
+    Invalid(u64),
+    ReadError(io::Error),
+    // Signal to reload configuration
+    Reload,
+    // Signal to exit, with reason
+    Shutdown(&'static str),
+}
+
+// Read requests on a separate thread, so signals can be handled while waiting for them
+fn spawn_reader(events: Sender<Event>, wire: Wire, input: Box<dyn io::Read + Send>) {
+    thread::spawn(move || {
+        let mut input = io::BufReader::new(input);
+        loop {
+            let event = match wire.read(&mut input) {
+                Ok(Some(event)) => event,
+                Ok(None) => break,
+                Err(err) => Event::ReadError(err),
+            };
+            if events.send(event).is_err() {
+                return;
+            }
+        }
+        let _ = events.send(Event::Request(None));
+    });
+}
+
+// Handle signals, including SIGTERM when our parent exits, so we don't outlive the editor even
+// if it's killed before it can close our input
+#[cfg(target_os = "linux")]
+fn spawn_signal_handler(events: Sender<Event>) -> Result<()> {
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+    let mut signals = signal_hook::iterator::Signals::new([SIGHUP, SIGINT, SIGTERM])?;
+    // SAFETY: plain system calls
+    let parent = unsafe { libc::getppid() };
+    if unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, SIGTERM) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // Parent may have exited before we asked to be notified
+    if unsafe { libc::getppid() } != parent {
+        let _ = events.send(Event::Shutdown("parent exited"));
+    }
+
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            let event = match signal {
+                SIGHUP => Event::Reload,
+                SIGINT => Event::Shutdown("SIGINT"),
+                _ if unsafe { libc::getppid() } != parent => Event::Shutdown("parent exited"),
+                _ => Event::Shutdown("SIGTERM"),
+            };
+            if events.send(event).is_err() {
+                return;
+            }
+        }
+    });
+    Ok(())
+}
+
+// Serve requests from the reader thread until told to stop, returning the reason
+fn serve(
+    clipipe: &mut Clipipe,
+    wire: Wire,
+    output: &mut impl Write,
+    queue: &Receiver<Event>,
+) -> Result<&'static str> {
+    Ok(loop {
+        // Wake up to make a coalesced copy, or to check whether we've been idle too long
+        let deadline = clipipe.pending.as_ref().map(|pending| pending.deadline);
+        let timeout = match deadline {
+            Some(deadline) => Some(deadline.saturating_duration_since(Instant::now())),
+            None => clipipe.options.idle_timeout,
+        };
+        let event = match timeout {
+            Some(timeout) => match queue.recv_timeout(timeout) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) if deadline.is_some() => {
+                    clipipe.flush();
+                    continue;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    // Exiting would lose a selection we own, so keep serving it
+                    match clipipe.backend.owns_selection() {
+                        Ok(false) => break "idle timeout",
+                        Ok(true) => log::log(Level::Debug, "idle with selection owned", Map::new()),
+                        Err(err) => log::error(Level::Warn, "couldn't check selection", &err),
+                    }
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            },
+            None => queue.recv()?,
+        };
+        let message = match event {
+            Event::Request(Some(message)) => message,
+            Event::Request(None) => break "end of input",
+            Event::Oversized(size, id) => {
+                wire.write(output, &clipipe.reject(size), id)?;
+                continue;
             }
-            Err(e) => {
-                let mut res = Map::new();
-                res.insert("success".into(), false.into());
-                error_to_json(&*e, &mut res);
-                res.into()
+            Event::Invalid(id) => {
+                wire.write(output, &clipipe.invalid(), Some(id))?;
+                continue;
+            }
+            Event::ReadError(err) => return Err(err.into()),
+            Event::Reload => {
+                if let Err(err) = clipipe.reload() {
+                    log::error(Level::Warn, "reload failed", &*err);
+                }
+                continue;
+            }
+            Event::Shutdown(reason) => {
+                // Acknowledge, in case the client is waiting for us to exit
+                let res = Response {
+                    reason: Some(reason.into()),
+                    ..clipipe.shutdown(clipipe.options.handoff)
+                };
+                let _ = wire.write(output, &res, None);
+                break reason;
             }
         };
 
-        writeln!(stdout, "{}", res)?;
-        stdout.flush()?;
+        let (res, id) = match message {
+            Message::Json(line) => (clipipe.handle(&line), None),
+            Message::Msgpack(value) => (clipipe.handle_value(&value), None),
+            Message::Call(request, id) => (clipipe.handle_value(&request), id),
+        };
+        wire.write(output, &res, id)?;
+        if clipipe.exiting {
+            break "shutdown request";
+        }
+    })
+}
+
+fn run(options: &Options) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+
+    match options.command {
+        Command::Serve => (),
+        // Quick query path, used to decide if binary is right version
+        Command::Query => {
+            Wire::default().write(&mut stdout, &Clipipe::query(), None)?;
+            return Ok(());
+        }
+        Command::Help => {
+            writeln!(stdout, "{}", Options::usage())?;
+            return Ok(());
+        }
+        Command::Version => {
+            writeln!(stdout, "clipipe {}", VERSION)?;
+            return Ok(());
+        }
+        Command::Doctor(ref args) => return doctor::run(options, args),
+        Command::Bench(ref args) => return bench::run(options, args),
+        Command::Replay(ref args) => return record::replay(options, args),
+        Command::Hold(ref args) => return compat::hold(options, args),
     }
+
+    let wire = Wire::new(options);
+    set_panic_hook(wire);
+    // Serve over a connection to Neovim, or stdio
+    let (input, mut output): rpc::Connection = match options.nvim {
+        Some(ref address) => rpc::connect(address)?,
+        None => (Box::new(io::stdin()), Box::new(stdout)),
+    };
+    let mut clipipe = Clipipe::new(options)?;
+    wire::set_max_request(options);
+    if options.nvim.is_some() {
+        wire::send(&mut output, &rpc::announce())?;
+    }
+
+    // Signals are processed between requests, so a request in progress always completes
+    let (events, queue) = mpsc::channel();
+    spawn_reader(events.clone(), wire, input);
+    #[cfg(target_os = "linux")]
+    spawn_signal_handler(events)?;
+
+    let reason = serve(&mut clipipe, wire, &mut output, &queue)?;
+    clipipe.flush();
+    if clipipe.options.keep_alive {
+        clipipe.persist();
+    }
+    let mut fields = Map::new();
+    fields.insert("reason".into(), reason.into());
+    log::log(Level::Info, "exiting", fields);
+    log::flush();
     Ok(())
 }
 
-fn main() -> io::Result<()> {
-    run().or_else(|err| writeln!(io::stderr(), "Error: {}", err))
+fn main() -> ExitCode {
+    // Act like another clipboard tool if invoked under its name
+    if let Some(personality) = Personality::detect() {
+        std::process::exit(compat::main(personality));
+    }
+
+    // Options determine whether we speak the protocol; assume so if they can't be parsed, since
+    // that's how the plugin runs us
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => return fatal(&*err, Some(Wire::default())),
+    };
+    let protocol = match options.command {
+        Command::Serve => true,
+        // Serving a replay speaks the protocol too
+        Command::Replay(ref args) => args.first().is_some_and(|arg| arg == "--serve"),
+        _ => false,
+    }
+    .then(|| Wire::new(&options));
+
+    if let Err(err) = log::init(&options) {
+        let err = io::Error::new(err.kind(), format!("Couldn't open log file: {}", err));
+        return fatal(&err, protocol);
+    }
+    match run(&options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => fatal(&*err, protocol),
+    }
 }
@@ -0,0 +1,249 @@
+// MessagePack encoding of protocol messages, as an alternative to JSON.  Only the part of the
+// format that maps onto JSON values is supported.  Strings travel as raw bytes, so clipboard
+// contents don't need escaping.
+use serde_json::{Map, Number, Value};
+use std::io::{self, BufRead, Read};
+
+// Deepest nesting accepted, like serde_json
+const MAX_DEPTH: usize = 128;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+struct Decoder<'a, R> {
+    input: &'a mut R,
+    // Bytes read so far
+    size: usize,
+    max: usize,
+}
+
+impl<R: BufRead> Decoder<'_, R> {
+    fn over(&self) -> bool {
+        self.size > self.max
+    }
+
+    fn bytes<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.input.read_exact(&mut buf)?;
+        self.size += N;
+        Ok(buf)
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        self.bytes::<1>().map(|[b]| b)
+    }
+
+    fn len16(&mut self) -> io::Result<usize> {
+        self.bytes().map(|b| u16::from_be_bytes(b) as usize)
+    }
+
+    fn len32(&mut self) -> io::Result<usize> {
+        self.bytes().map(|b| u32::from_be_bytes(b) as usize)
+    }
+
+    // String or binary, which must be UTF-8 either way.  Once the message is too large,
+    // strings are skipped instead of buffered.
+    fn string(&mut self, len: usize) -> io::Result<Value> {
+        self.size = self.size.saturating_add(len);
+        if self.over() {
+            let skipped = io::copy(
+                &mut Read::take(&mut *self.input, len as u64),
+                &mut io::sink(),
+            )?;
+            if skipped < len as u64 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            return Ok(Value::Null);
+        }
+        // The length isn't trusted with an allocation, since there may be no maximum
+        let mut buf = vec![];
+        Read::take(&mut *self.input, len as u64).read_to_end(&mut buf)?;
+        if buf.len() < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        String::from_utf8(buf)
+            .map(Value::String)
+            .map_err(|_| invalid("MessagePack string isn't valid UTF-8"))
+    }
+
+    fn array(&mut self, len: usize, depth: usize) -> io::Result<Value> {
+        let mut array = vec![];
+        for _ in 0..len {
+            let value = self.value(depth + 1)?;
+            if !self.over() {
+                array.push(value);
+            }
+        }
+        Ok(Value::Array(array))
+    }
+
+    fn map(&mut self, len: usize, depth: usize) -> io::Result<Value> {
+        let mut map = Map::new();
+        for _ in 0..len {
+            let key = self.value(depth + 1)?;
+            let value = self.value(depth + 1)?;
+            match key {
+                Value::String(key) => {
+                    map.insert(key, value);
+                }
+                _ if self.over() => (),
+                _ => return Err(invalid("MessagePack map key isn't a string")),
+            }
+        }
+        Ok(Value::Object(map))
+    }
+
+    fn float(value: f64) -> Value {
+        Number::from_f64(value).map_or(Value::Null, Value::Number)
+    }
+
+    fn value(&mut self, depth: usize) -> io::Result<Value> {
+        if depth > MAX_DEPTH {
+            return Err(invalid("MessagePack value nested too deeply"));
+        }
+        let tag = self.byte()?;
+        Ok(match tag {
+            0x00..=0x7f => tag.into(),
+            0x80..=0x8f => self.map((tag & 0x0f) as usize, depth)?,
+            0x90..=0x9f => self.array((tag & 0x0f) as usize, depth)?,
+            0xa0..=0xbf => self.string((tag & 0x1f) as usize)?,
+            0xc0 => Value::Null,
+            0xc2 => false.into(),
+            0xc3 => true.into(),
+            0xc4 | 0xd9 => {
+                let len = self.byte()? as usize;
+                self.string(len)?
+            }
+            0xc5 | 0xda => {
+                let len = self.len16()?;
+                self.string(len)?
+            }
+            0xc6 | 0xdb => {
+                let len = self.len32()?;
+                self.string(len)?
+            }
+            0xca => Self::float(f32::from_be_bytes(self.bytes()?) as f64),
+            0xcb => Self::float(f64::from_be_bytes(self.bytes()?)),
+            0xcc => self.byte()?.into(),
+            0xcd => u16::from_be_bytes(self.bytes()?).into(),
+            0xce => u32::from_be_bytes(self.bytes()?).into(),
+            0xcf => u64::from_be_bytes(self.bytes()?).into(),
+            0xd0 => i8::from_be_bytes(self.bytes()?).into(),
+            0xd1 => i16::from_be_bytes(self.bytes()?).into(),
+            0xd2 => i32::from_be_bytes(self.bytes()?).into(),
+            0xd3 => i64::from_be_bytes(self.bytes()?).into(),
+            0xdc => {
+                let len = self.len16()?;
+                self.array(len, depth)?
+            }
+            0xdd => {
+                let len = self.len32()?;
+                self.array(len, depth)?
+            }
+            0xde => {
+                let len = self.len16()?;
+                self.map(len, depth)?
+            }
+            0xdf => {
+                let len = self.len32()?;
+                self.map(len, depth)?
+            }
+            0xe0..=0xff => (tag as i8).into(),
+            // Extension types and the unused tag
+            _ => return Err(invalid("unsupported MessagePack type")),
+        })
+    }
+}
+
+// Read a value and its encoded size, or None at end of input.  A value larger than `max` is read
+// to the end without buffering its strings; the caller should check the size.
+pub fn read(input: &mut impl BufRead, max: usize) -> io::Result<Option<(Value, usize)>> {
+    if input.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let mut decoder = Decoder {
+        input,
+        size: 0,
+        max,
+    };
+    let value = decoder.value(0)?;
+    Ok(Some((value, decoder.size)))
+}
+
+// Append the header of a string, array or map, using the smallest form that fits: the fixed
+// form with the length in the tag, then 8 (strings only), 16 and 32 bit lengths
+fn header(out: &mut Vec<u8>, len: usize, fix: (u8, usize), tags: (Option<u8>, u8, u8)) {
+    let (fixtag, fixmax) = fix;
+    match tags.0 {
+        _ if len <= fixmax => out.push(fixtag | len as u8),
+        Some(tag) if len <= u8::MAX as usize => out.extend([tag, len as u8]),
+        _ if len <= u16::MAX as usize => {
+            out.push(tags.1);
+            out.extend((len as u16).to_be_bytes());
+        }
+        _ => {
+            out.push(tags.2);
+            out.extend((len as u32).to_be_bytes());
+        }
+    }
+}
+
+fn encode_str(out: &mut Vec<u8>, s: &str) {
+    header(out, s.len(), (0xa0, 31), (Some(0xd9), 0xda, 0xdb));
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn encode_number(out: &mut Vec<u8>, n: &Number) {
+    if let Some(n) = n.as_u64() {
+        match n {
+            0..=0x7f => out.push(n as u8),
+            0x80..=0xff => out.extend([0xcc, n as u8]),
+            0x100..=0xffff => {
+                out.push(0xcd);
+                out.extend((n as u16).to_be_bytes());
+            }
+            0x10000..=0xffff_ffff => {
+                out.push(0xce);
+                out.extend((n as u32).to_be_bytes());
+            }
+            _ => {
+                out.push(0xcf);
+                out.extend(n.to_be_bytes());
+            }
+        }
+    } else if let Some(n) = n.as_i64() {
+        if n >= -32 {
+            out.push(n as i8 as u8);
+        } else {
+            out.push(0xd3);
+            out.extend(n.to_be_bytes());
+        }
+    } else {
+        out.push(0xcb);
+        out.extend(n.as_f64().unwrap_or(f64::NAN).to_be_bytes());
+    }
+}
+
+// Append the encoding of a value
+pub fn encode(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(b) => out.push(0xc2 | *b as u8),
+        Value::Number(n) => encode_number(out, n),
+        Value::String(s) => encode_str(out, s),
+        Value::Array(array) => {
+            header(out, array.len(), (0x90, 15), (None, 0xdc, 0xdd));
+            for value in array {
+                encode(out, value);
+            }
+        }
+        Value::Object(map) => {
+            header(out, map.len(), (0x80, 15), (None, 0xde, 0xdf));
+            for (key, value) in map {
+                encode_str(out, key);
+                encode(out, value);
+            }
+        }
+    }
+}
@@ -0,0 +1,488 @@
+// Command line, environment and configuration file options
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::Result;
+use crate::config::{self, Config};
+use crate::log::Level;
+
+const USAGE: &str = "\
+Usage: clipipe [OPTIONS] [COMMAND [ARGS...]]
+
+Persistent clipboard provider for Neovim.  Without a command, serves JSON requests on
+stdin, one per line unless --framing or --encoding is given.
+
+Commands:
+  doctor [--json]           Diagnose the clipboard environment
+  bench [--sizes N,...] [--iterations N] [--json]
+                            Benchmark clipboard latency and throughput
+  replay [--serve] FILE     Replay a session recorded with --record against a fake backend;
+                            with --serve, serve requests from stdin against its backend calls
+  hold SELECTION [REGTYPE]  Serve stdin as clipboard or primary from a background process
+                            until replaced, with a Vim register type
+
+Options:
+  --config PATH             Configuration file
+                            [default: $XDG_CONFIG_HOME/clipipe/config.toml]
+  --framing MODE            Message framing: lines (default) or content-length
+  --encoding NAME           Message encoding: json (default) or msgpack
+  --rpc                     Serve Neovim msgpack-RPC methods clipipe_copy, clipipe_paste,
+                            etc.
+  --nvim ADDRESS            Connect to a Neovim server, e.g. $NVIM, and serve msgpack-RPC
+  --vim                     Serve a Vim channel in JSON mode: [id, request] lines
+  --backend NAME[,NAME...]  Clipboard backends to try in order: auto, wayland, x11 (Linux),
+                            windows
+  --timeout MS              Timeout for reading the selection from its owner [default: 100]
+  --log-file PATH           Append log records to PATH as JSON lines instead of stderr
+  --log-level LEVEL         Log level: error, warn, info, debug or trace
+                            [default: info with --log-file, otherwise error]
+  --log-contents            Log clipboard contents instead of redacting them
+  --max-request BYTES       Maximum request size
+  --max-paste BYTES         Maximum size of pasted data
+  --oversized POLICY        Policy for larger pastes: reject (default) or truncate
+  --spill BYTES             Return larger pastes in a temporary file instead of inline
+  --record PATH             Record requests, responses and backend calls to PATH
+  --handoff                 Hand the clipboard to a clipboard manager when shut down
+  --no-keep-alive           Don't keep owned selections available after exiting
+  --idle-timeout SECS       Exit after SECS without requests, unless we own a selection
+  --coalesce MS             Combine copies to the same selection within MS, keeping the
+                            latest
+  --line-endings POLICY     Line ending policy: convert (Windows default) or keep
+  --keep-line-endings       Same as --line-endings keep
+  --query                   Print version information as JSON and exit
+  -V, --version             Print version and exit
+  -h, --help                Print this help and exit
+
+Options can also be set through the environment, e.g. CLIPIPE_CONFIG, CLIPIPE_BACKEND,
+CLIPIPE_TIMEOUT, CLIPIPE_LOG_FILE, CLIPIPE_LOG_LEVEL, CLIPIPE_MAX_REQUEST and
+CLIPIPE_LINE_ENDINGS.  The command line takes precedence over the environment, which takes
+precedence over the configuration file.";
+
+// Which clipboard backend to use
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BackendKind {
+    #[cfg(target_os = "linux")]
+    Wayland,
+    #[cfg(target_os = "linux")]
+    X11,
+    #[cfg(target_os = "windows")]
+    Windows,
+}
+
+impl BackendKind {
+    pub fn parse(name: &str) -> Option<BackendKind> {
+        Some(match name {
+            #[cfg(target_os = "linux")]
+            "wayland" => BackendKind::Wayland,
+            #[cfg(target_os = "linux")]
+            "x11" => BackendKind::X11,
+            #[cfg(target_os = "windows")]
+            "windows" => BackendKind::Windows,
+            _ => return None,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            #[cfg(target_os = "linux")]
+            BackendKind::Wayland => "wayland",
+            #[cfg(target_os = "linux")]
+            BackendKind::X11 => "x11",
+            #[cfg(target_os = "windows")]
+            BackendKind::Windows => "windows",
+        }
+    }
+
+    // Parse backend chain; `auto` (empty chain) picks based on environment
+    pub fn parse_chain<'a>(names: impl IntoIterator<Item = &'a str>) -> Option<Vec<BackendKind>> {
+        let names: Vec<&str> = names.into_iter().map(str::trim).collect();
+        if names == ["auto"] {
+            return Some(vec![]);
+        }
+        names.into_iter().map(Self::parse).collect()
+    }
+}
+
+// Line ending handling for platforms where the clipboard convention differs from Neovim's
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LineEndings {
+    // Convert between \n and the platform convention
+    Convert,
+    // Pass through unmodified
+    Keep,
+}
+
+impl LineEndings {
+    pub fn parse(name: &str) -> Option<LineEndings> {
+        Some(match name {
+            "convert" => LineEndings::Convert,
+            "keep" => LineEndings::Keep,
+            _ => return None,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LineEndings::Convert => "convert",
+            LineEndings::Keep => "keep",
+        }
+    }
+}
+
+// What to do with pasted data larger than the maximum
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Oversized {
+    // Fail the paste
+    Reject,
+    // Paste the beginning, followed by a marker
+    Truncate,
+}
+
+impl Oversized {
+    pub fn parse(name: &str) -> Option<Oversized> {
+        Some(match name {
+            "reject" => Oversized::Reject,
+            "truncate" => Oversized::Truncate,
+            _ => return None,
+        })
+    }
+}
+
+// How protocol messages are delimited
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Framing {
+    // One JSON object per line
+    Lines,
+    // `Content-Length` header before each message, as in LSP
+    ContentLength,
+}
+
+impl Framing {
+    pub fn parse(name: &str) -> Option<Framing> {
+        Some(match name {
+            "lines" => Framing::Lines,
+            "content-length" => Framing::ContentLength,
+            _ => return None,
+        })
+    }
+}
+
+// How protocol messages are encoded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Json,
+    // MessagePack, which carries strings without escaping
+    Msgpack,
+}
+
+impl Encoding {
+    pub fn parse(name: &str) -> Option<Encoding> {
+        Some(match name {
+            "json" => Encoding::Json,
+            "msgpack" => Encoding::Msgpack,
+            _ => return None,
+        })
+    }
+}
+
+// What to do
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    // Serve requests on stdin
+    Serve,
+    // Print version information as JSON
+    Query,
+    Help,
+    Version,
+    // Subcommands, with their arguments
+    Doctor(Vec<String>),
+    Bench(Vec<String>),
+    Replay(Vec<String>),
+    Hold(Vec<String>),
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub command: Command,
+    // Message framing of the protocol, fixed for the session
+    pub framing: Framing,
+    pub encoding: Encoding,
+    // Serve Neovim msgpack-RPC on stdio
+    pub rpc: bool,
+    // Neovim server to connect to and serve msgpack-RPC over instead
+    pub nvim: Option<String>,
+    // Serve a Vim channel in JSON mode on stdio
+    pub vim: bool,
+    // Configuration file, if not the default
+    pub config: Option<PathBuf>,
+    // Backends to try in order; empty means pick based on environment
+    pub backends: Vec<BackendKind>,
+    // Timeout waiting for selection owner to respond to a paste
+    pub timeout: Duration,
+    // Where to write log records, if not stderr
+    pub log_file: Option<PathBuf>,
+    // Log level, if not the default for the destination
+    pub log_level: Option<Level>,
+    // Log clipboard contents instead of redacting them
+    pub log_contents: bool,
+    // Maximum size of a single request, in bytes
+    pub max_request: Option<usize>,
+    // Maximum size of pasted data, in bytes
+    pub max_paste: Option<usize>,
+    pub oversized: Oversized,
+    // Pastes larger than this are returned in a temporary file
+    pub spill: Option<usize>,
+    // Hand clipboard contents to a clipboard manager on shutdown
+    pub handoff: bool,
+    // Keep owned selections available after exiting
+    pub keep_alive: bool,
+    // Exit after this long without requests
+    pub idle_timeout: Option<Duration>,
+    // Window in which copies to the same selection are combined; zero to copy immediately
+    pub coalesce: Duration,
+    // Where to record the session, if anywhere
+    pub record: Option<PathBuf>,
+    pub line_endings: LineEndings,
+    // Preferred MIME types for pastes, most preferred first (Wayland)
+    pub mime_priority: Vec<String>,
+    // Command line arguments, kept for reloading
+    args: Vec<String>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            command: Command::Serve,
+            framing: Framing::Lines,
+            encoding: Encoding::Json,
+            rpc: false,
+            nvim: None,
+            vim: false,
+            config: None,
+            backends: vec![],
+            timeout: Duration::from_millis(100),
+            log_file: None,
+            log_level: None,
+            log_contents: false,
+            max_request: None,
+            max_paste: None,
+            oversized: Oversized::Reject,
+            spill: None,
+            handoff: false,
+            keep_alive: true,
+            idle_timeout: None,
+            coalesce: Duration::ZERO,
+            record: None,
+            line_endings: LineEndings::Convert,
+            mime_priority: vec![],
+            args: vec![],
+        }
+    }
+}
+
+// Invalid command line or environment
+#[derive(Debug)]
+pub struct UsageError(String);
+
+impl std::fmt::Display for UsageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        write!(f, "{} (see --help)", self.0)
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+pub fn usage_error(message: String) -> Box<dyn std::error::Error> {
+    UsageError(message).into()
+}
+
+impl Options {
+    pub fn usage() -> &'static str {
+        USAGE
+    }
+
+    // Set option by name from a string value
+    fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let invalid = || usage_error(format!("Invalid value for {}: {}", name, value));
+        match name {
+            "config" => self.config = Some(value.into()),
+            "framing" => self.framing = Framing::parse(value).ok_or_else(invalid)?,
+            "encoding" => self.encoding = Encoding::parse(value).ok_or_else(invalid)?,
+            "nvim" => self.nvim = Some(value.into()),
+            "backend" => {
+                self.backends = BackendKind::parse_chain(value.split(',')).ok_or_else(invalid)?
+            }
+            "timeout" => {
+                self.timeout = Duration::from_millis(value.parse().map_err(|_| invalid())?)
+            }
+            "log-file" => self.log_file = Some(value.into()),
+            "log-level" => self.log_level = Some(Level::parse(value).ok_or_else(invalid)?),
+            "max-request" => self.max_request = Some(value.parse().map_err(|_| invalid())?),
+            "max-paste" => self.max_paste = Some(value.parse().map_err(|_| invalid())?),
+            "oversized" => self.oversized = Oversized::parse(value).ok_or_else(invalid)?,
+            "spill" => self.spill = Some(value.parse().map_err(|_| invalid())?),
+            "record" => self.record = Some(value.into()),
+            "idle-timeout" => {
+                self.idle_timeout = Some(Duration::from_secs(value.parse().map_err(|_| invalid())?))
+            }
+            "coalesce" => {
+                self.coalesce = Duration::from_millis(value.parse().map_err(|_| invalid())?)
+            }
+            "line-endings" => self.line_endings = LineEndings::parse(value).ok_or_else(invalid)?,
+            _ => unreachable!("unknown option {}", name),
+        }
+        Ok(())
+    }
+
+    // Options which take a value, by long name and environment variable
+    const VALUED: &[(&str, &str)] = &[
+        ("config", "CLIPIPE_CONFIG"),
+        ("framing", "CLIPIPE_FRAMING"),
+        ("encoding", "CLIPIPE_ENCODING"),
+        ("nvim", "CLIPIPE_NVIM"),
+        ("backend", "CLIPIPE_BACKEND"),
+        ("timeout", "CLIPIPE_TIMEOUT"),
+        ("log-file", "CLIPIPE_LOG_FILE"),
+        ("log-level", "CLIPIPE_LOG_LEVEL"),
+        ("max-request", "CLIPIPE_MAX_REQUEST"),
+        ("max-paste", "CLIPIPE_MAX_PASTE"),
+        ("oversized", "CLIPIPE_OVERSIZED"),
+        ("spill", "CLIPIPE_SPILL"),
+        ("line-endings", "CLIPIPE_LINE_ENDINGS"),
+        ("record", "CLIPIPE_RECORD"),
+        ("idle-timeout", "CLIPIPE_IDLE_TIMEOUT"),
+        ("coalesce", "CLIPIPE_COALESCE"),
+    ];
+
+    fn apply_env(&mut self) -> Result<()> {
+        for (name, var) in Self::VALUED {
+            if let Some(value) = env::var(var).ok().filter(|v| !v.is_empty()) {
+                self.set(name, &value)
+                    .map_err(|_| usage_error(format!("Invalid value for {}: {}", var, value)))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: &[String]) -> Result<()> {
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag, Some(value)),
+                _ => (arg.as_ref(), None),
+            };
+            match flag {
+                "--query" => self.command = Command::Query,
+                "-h" | "--help" => self.command = Command::Help,
+                "-V" | "--version" => self.command = Command::Version,
+                "--keep-line-endings" => self.line_endings = LineEndings::Keep,
+                "--log-contents" => self.log_contents = true,
+                "--handoff" => self.handoff = true,
+                "--no-keep-alive" => self.keep_alive = false,
+                "--rpc" => self.rpc = true,
+                "--vim" => self.vim = true,
+                "doctor" => {
+                    self.command = Command::Doctor(args.cloned().collect());
+                    break;
+                }
+                "bench" => {
+                    self.command = Command::Bench(args.cloned().collect());
+                    break;
+                }
+                "replay" => {
+                    self.command = Command::Replay(args.cloned().collect());
+                    break;
+                }
+                "hold" => {
+                    self.command = Command::Hold(args.cloned().collect());
+                    break;
+                }
+                _ => {
+                    let name = flag
+                        .strip_prefix("--")
+                        .filter(|name| Self::VALUED.iter().any(|(n, _)| n == name));
+                    let Some(name) = name else {
+                        return Err(usage_error(format!("Unknown argument: {}", arg)));
+                    };
+                    let value = match inline {
+                        Some(value) => value,
+                        None => args
+                            .next()
+                            .ok_or_else(|| usage_error(format!("Missing value for {}", flag)))?,
+                    };
+                    self.set(name, value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Parse options from the configuration file, the environment and the given command line
+    // arguments (sans argv[0]), in increasing order of precedence
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Options> {
+        let args: Vec<String> = args.into_iter().collect();
+
+        // Queries, help and the version don't depend on the configuration or environment, so
+        // mistakes there don't make the plugin reject a working binary
+        let mut options = Options::default();
+        options.apply_args(&args)?;
+        if matches!(
+            options.command,
+            Command::Query | Command::Help | Command::Version
+        ) {
+            options.args = args;
+            return Ok(options);
+        }
+
+        // Find configuration file first, since everything else overrides it
+        let mut options = Options::default();
+        options.apply_env()?;
+        options.apply_args(&args)?;
+        let path = options.config.take();
+
+        let mut options = Options::default();
+        if let Some(config) = Config::load(path.as_deref()).map_err(config::Error)? {
+            config.apply(&mut options).map_err(config::Error)?;
+        }
+        options.apply_env()?;
+        options.apply_args(&args)?;
+        options.args = args;
+        Ok(options)
+    }
+
+    // Parse options from the configuration file and environment only
+    pub fn from_env() -> Result<Options> {
+        Self::parse([])
+    }
+
+    // Re-read configuration with the same command line
+    pub fn reload(&self) -> Result<Options> {
+        Self::parse(self.args.clone())
+    }
+
+    // Arguments which reproduce the backend-related options, for running child processes
+    pub fn backend_args(&self) -> Vec<String> {
+        let backends = match self.backends.is_empty() {
+            true => "auto".into(),
+            false => self
+                .backends
+                .iter()
+                .map(|b| b.name())
+                .collect::<Vec<_>>()
+                .join(","),
+        };
+        let mut args = vec![
+            format!("--backend={}", backends),
+            format!("--timeout={}", self.timeout.as_millis()),
+            format!("--line-endings={}", self.line_endings.name()),
+        ];
+        if let Some(ref config) = self.config {
+            args.push(format!("--config={}", config.display()));
+        }
+        if let Some(max) = self.max_request {
+            args.push(format!("--max-request={}", max));
+        }
+        args
+    }
+}
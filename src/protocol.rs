@@ -0,0 +1,377 @@
+// Requests and responses of the JSON protocol, shared by the server, the wire encodings, the
+// recorder and the benchmark client.  This file only depends on serde, so the mock server and
+// the tests include it too.
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::borrow::Cow;
+
+// Source of a paste
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    #[default]
+    Default,
+    Primary,
+    Clipboard,
+}
+
+impl Source {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Source::Default => "default",
+            Source::Primary => "primary",
+            Source::Clipboard => "clipboard",
+        }
+    }
+}
+
+// Destination of a copy
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dest {
+    #[default]
+    Default,
+    Primary,
+    Clipboard,
+    Both,
+}
+
+impl Dest {
+    pub fn parse(name: &str) -> Option<Dest> {
+        Some(match name {
+            "default" => Dest::Default,
+            "primary" => Dest::Primary,
+            "clipboard" => Dest::Clipboard,
+            "both" => Dest::Both,
+            _ => return None,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Dest::Default => "default",
+            Dest::Primary => "primary",
+            Dest::Clipboard => "clipboard",
+            Dest::Both => "both",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Request<'a> {
+    // Copy `data`, the contents of `file`, which is removed once read, or `lines` of a Neovim
+    // register of type `regtype`
+    Copy {
+        #[serde(default, deserialize_with = "clipboard")]
+        clipboard: Dest,
+        #[serde(borrow, default, deserialize_with = "data")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<Cow<'a, str>>,
+        #[serde(borrow, default, deserialize_with = "file")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        file: Option<Cow<'a, str>>,
+        #[serde(borrow, default, deserialize_with = "lines")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        lines: Option<Vec<Cow<'a, str>>>,
+        #[serde(borrow, default, deserialize_with = "regtype")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        regtype: Option<Cow<'a, str>>,
+    },
+    // Paste, unless the contents still match the given change token.  With `lines`, the
+    // contents are returned as lines and a register type.
+    Paste {
+        #[serde(default, deserialize_with = "clipboard")]
+        clipboard: Source,
+        #[serde(borrow, default, deserialize_with = "if_changed_since")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        if_changed_since: Option<Cow<'a, str>>,
+        #[serde(default, deserialize_with = "as_lines")]
+        #[serde(skip_serializing_if = "is_false")]
+        lines: bool,
+    },
+    // Version information
+    Query,
+    // Re-read configuration
+    Reload,
+    // Exit after responding, optionally overriding whether to hand off the clipboard
+    Shutdown {
+        #[serde(default, deserialize_with = "handoff")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        handoff: Option<bool>,
+    },
+    // Describe the protocol
+    Schema,
+    // Run several requests in order, holding the clipboard lock throughout
+    Batch {
+        #[serde(borrow, deserialize_with = "requests")]
+        requests: Vec<Request<'a>>,
+    },
+}
+
+impl Request<'_> {
+    pub fn action(&self) -> &'static str {
+        match *self {
+            Request::Copy { .. } => "copy",
+            Request::Paste { .. } => "paste",
+            Request::Query => "query",
+            Request::Reload => "reload",
+            Request::Shutdown { .. } => "shutdown",
+            Request::Schema => "schema",
+            Request::Batch { .. } => "batch",
+        }
+    }
+}
+
+// String borrowed from the request line unless it has escapes
+struct Text<'a>(Cow<'a, str>);
+
+impl<'de> Deserialize<'de> for Text<'de> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TextVisitor;
+
+        impl<'de> Visitor<'de> for TextVisitor {
+            type Value = Text<'de>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a string")
+            }
+
+            fn visit_borrowed_str<E: de::Error>(self, v: &'de str) -> Result<Self::Value, E> {
+                Ok(Text(Cow::Borrowed(v)))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                Ok(Text(Cow::Owned(v.into())))
+            }
+
+            fn visit_string<E: de::Error>(self, v: String) -> Result<Self::Value, E> {
+                Ok(Text(Cow::Owned(v)))
+            }
+        }
+
+        deserializer.deserialize_str(TextVisitor)
+    }
+}
+
+// Deserialize a field, naming it in errors
+fn field<'de, D: Deserializer<'de>, T: Deserialize<'de>>(
+    name: &str,
+    deserializer: D,
+) -> Result<T, D::Error> {
+    T::deserialize(deserializer)
+        .map_err(|err| de::Error::custom(format_args!("invalid `{}`: {}", name, err)))
+}
+
+fn clipboard<'de, D: Deserializer<'de>, T: Deserialize<'de>>(d: D) -> Result<T, D::Error> {
+    field("clipboard", d)
+}
+
+fn data<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Cow<'de, str>>, D::Error> {
+    field("data", d).map(|Text(text)| Some(text))
+}
+
+fn file<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Cow<'de, str>>, D::Error> {
+    field("file", d).map(|Text(text)| Some(text))
+}
+
+fn if_changed_since<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Cow<'de, str>>, D::Error> {
+    field("if_changed_since", d).map(|Text(text)| Some(text))
+}
+
+fn lines<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<Cow<'de, str>>>, D::Error> {
+    field("lines", d)
+        .map(|lines: Vec<Text>| Some(lines.into_iter().map(|Text(line)| line).collect()))
+}
+
+fn regtype<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Cow<'de, str>>, D::Error> {
+    field("regtype", d).map(|Text(text)| Some(text))
+}
+
+// Paste's `lines` flag
+fn as_lines<'de, D: Deserializer<'de>>(d: D) -> Result<bool, D::Error> {
+    field("lines", d)
+}
+
+fn handoff<'de, D: Deserializer<'de>>(d: D) -> Result<Option<bool>, D::Error> {
+    field("handoff", d).map(Some)
+}
+
+fn requests<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Request<'de>>, D::Error> {
+    field("requests", d)
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+// Error and the chain of errors that caused it
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct ErrorInfo {
+    pub message: String,
+    // Source location, for panics
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<Box<ErrorInfo>>,
+}
+
+impl ErrorInfo {
+    pub fn new(error: &(dyn std::error::Error + '_)) -> ErrorInfo {
+        ErrorInfo {
+            message: error.to_string(),
+            location: None,
+            source: error.source().map(|source| Box::new(Self::new(source))),
+        }
+    }
+}
+
+// Response to any request.  Only the fields relevant to the request are present.
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Response<'a> {
+    pub success: bool,
+    // Failure, with the error's source chain
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<Box<ErrorInfo>>,
+    // Query
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    // Paste: the data, its lines and register type, or the file it was written to and its size
+    #[serde(borrow, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Cow<'a, str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lines: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub regtype: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mime: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub unchanged: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub truncated: bool,
+    // Reload
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restart_required: Option<bool>,
+    // Shutdown, whether requested or not
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub shutdown: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub handoff: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    // Schema
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schema: Option<Value>,
+    // Batch: the response to each request, in order
+    #[serde(borrow, skip_serializing_if = "Option::is_none")]
+    pub results: Option<Vec<Response<'a>>>,
+    // Error that made clipipe exit
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub fatal: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<u8>,
+}
+
+impl Response<'_> {
+    pub fn ok() -> Self {
+        Response {
+            success: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn error(error: ErrorInfo) -> Self {
+        Response {
+            success: false,
+            message: Some(error.message),
+            source: error.source,
+            ..Default::default()
+        }
+    }
+}
+
+// JSON Schema describing requests and responses
+pub fn schema() -> Value {
+    let string = json!({"type": "string"});
+    let boolean = json!({"type": "boolean"});
+    let dest = json!({"enum": ["default", "primary", "clipboard", "both"]});
+    let source = json!({"enum": ["default", "primary", "clipboard"]});
+    let lines = json!({"type": "array", "items": string});
+    let action = |name: &str, properties: Value| {
+        let mut properties = properties;
+        properties["action"] = json!({"const": name});
+        json!({"type": "object", "required": ["action"], "properties": properties})
+    };
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "$defs": {
+            "request": {"oneOf": [
+                action("copy", json!({
+                    "clipboard": dest,
+                    "data": string,
+                    "file": string,
+                    "lines": lines,
+                    "regtype": string,
+                })),
+                action("paste", json!({
+                    "clipboard": source,
+                    "if_changed_since": string,
+                    "lines": boolean,
+                })),
+                action("query", json!({})),
+                action("reload", json!({})),
+                action("shutdown", json!({"handoff": boolean})),
+                action("schema", json!({})),
+                action("batch", json!({"requests": {
+                    "type": "array",
+                    "items": {"$ref": "#/$defs/request"},
+                }})),
+            ]},
+            "error": {
+                "type": "object",
+                "required": ["message"],
+                "properties": {
+                    "message": string,
+                    "location": string,
+                    "source": {"$ref": "#/$defs/error"},
+                },
+            },
+            "response": {
+                "type": "object",
+                "required": ["success"],
+                "properties": {
+                    "success": boolean,
+                    "message": string,
+                    "source": {"$ref": "#/$defs/error"},
+                    "version": string,
+                    "data": string,
+                    "lines": lines,
+                    "regtype": string,
+                    "file": string,
+                    "size": {"type": "integer", "minimum": 0},
+                    "mime": string,
+                    "token": string,
+                    "unchanged": boolean,
+                    "truncated": boolean,
+                    "restart_required": boolean,
+                    "shutdown": boolean,
+                    "handoff": boolean,
+                    "reason": string,
+                    "schema": {"type": "object"},
+                    "results": {"type": "array", "items": {"$ref": "#/$defs/response"}},
+                    "fatal": boolean,
+                    "exit_code": {"type": "integer", "minimum": 0, "maximum": 255},
+                },
+            },
+        },
+    })
+}
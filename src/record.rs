@@ -0,0 +1,319 @@
+// Session recording (`--record`) and replay against a fake backend (`clipipe replay`)
+use serde_json::{Map, Value};
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Mutex, mpsc};
+use std::time::Duration;
+
+use crate::clipboard::{self, Backend, Data, Dest, Error, ErrorDetail, Motion, Source};
+use crate::log::{self, Level};
+use crate::options::{BackendKind, Options, usage_error};
+use crate::protocol::Response;
+use crate::wire::{self, Wire};
+use crate::{Clipipe, Result};
+
+// Recording being written, if any.  Each line is a JSON object: a header, then for each request
+// the backend calls it made followed by the request itself.
+static RECORDING: Mutex<Option<File>> = Mutex::new(None);
+
+fn write(entry: Value) {
+    let mut recording = RECORDING.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(ref mut file) = *recording
+        && let Err(err) = writeln!(file, "{}", entry)
+    {
+        log::error(Level::Warn, "couldn't write recording", &err);
+    }
+}
+
+// Start recording to the given file, which only we can read since it has clipboard contents
+pub fn start(path: &Path, kind: BackendKind) -> io::Result<()> {
+    let mut options = File::options();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let file = options.open(path)?;
+    *RECORDING.lock().unwrap_or_else(|e| e.into_inner()) = Some(file);
+    write(serde_json::json!({"version": crate::VERSION, "backend": kind.name()}));
+    Ok(())
+}
+
+// Record a request and its response
+pub fn request(request: &(impl Display + ?Sized), response: &Response, elapsed: Duration) {
+    if RECORDING
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .is_none()
+    {
+        return;
+    }
+    write(serde_json::json!({
+        "request": request.to_string(),
+        "response": response,
+        "elapsed_ms": elapsed.as_secs_f64() * 1000.0,
+    }));
+}
+
+// Convert backend call result to JSON
+fn outcome<T>(
+    result: &clipboard::Result<T>,
+    ok: impl FnOnce(&T, &mut Map<String, Value>),
+) -> Value {
+    let mut map = Map::new();
+    match result {
+        Ok(value) => {
+            map.insert("success".into(), true.into());
+            ok(value, &mut map);
+        }
+        Err(err) => {
+            map.insert("success".into(), false.into());
+            log::error_to_json(err, &mut map);
+        }
+    }
+    map.into()
+}
+
+// Backend wrapper which records calls and their results
+pub struct Recorder<B> {
+    backend: B,
+}
+
+impl<B: Backend> Recorder<B> {
+    pub fn new(backend: B) -> Recorder<B> {
+        Recorder { backend }
+    }
+}
+
+impl<B: Backend> Backend for Recorder<B> {
+    fn copy(&mut self, dest: Dest, data: &str, motion: Option<Motion>) -> clipboard::Result<()> {
+        let clipboard = dest.name();
+        let res = self.backend.copy(dest, data, motion);
+        let mut entry = serde_json::json!({
+            "op": "copy",
+            "clipboard": clipboard,
+            "data": data,
+            "result": outcome(&res, |_, _| ()),
+        });
+        if let Some(motion) = motion {
+            entry["regtype"] = motion.regtype().into();
+        }
+        write(serde_json::json!({ "backend": entry }));
+        res
+    }
+
+    fn paste(&mut self, source: Source) -> clipboard::Result<Data> {
+        let clipboard = source.name();
+        let res = self.backend.paste(source);
+        write(serde_json::json!({"backend": {
+            "op": "paste",
+            "clipboard": clipboard,
+            "result": outcome(&res, |data, map| {
+                map.insert("data".into(), data.data.clone().into());
+                if let Some(ref mime) = data.mime {
+                    map.insert("mime".into(), mime.clone().into());
+                }
+                if let Some(motion) = data.motion {
+                    map.insert("regtype".into(), motion.regtype().into());
+                }
+            }),
+        }}));
+        res
+    }
+
+    fn configure(&mut self, options: &Options) {
+        self.backend.configure(options)
+    }
+
+    fn handoff(&mut self) -> clipboard::Result<bool> {
+        self.backend.handoff()
+    }
+
+    fn owned(&mut self) -> clipboard::Result<Vec<(Dest, String, Option<Motion>)>> {
+        self.backend.owned()
+    }
+
+    fn lock(&mut self) -> clipboard::Result<()> {
+        self.backend.lock()
+    }
+
+    fn unlock(&mut self) {
+        self.backend.unlock()
+    }
+}
+
+// Error reconstructed from a recording
+#[derive(Debug)]
+struct Replayed {
+    message: String,
+    source: Option<Box<Replayed>>,
+}
+
+impl Replayed {
+    fn new(message: impl Into<String>) -> Replayed {
+        Replayed {
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    fn from_json(value: &Value) -> Replayed {
+        Replayed {
+            message: match value.get("message") {
+                Some(Value::String(message)) => message.clone(),
+                _ => "unknown error".into(),
+            },
+            source: value
+                .get("source")
+                .map(|source| Box::new(Self::from_json(source))),
+        }
+    }
+}
+
+impl std::fmt::Display for Replayed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Replayed {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source.as_ref().map(|e| e.as_ref() as _)
+    }
+}
+
+// Fake backend which plays back recorded backend calls in order
+struct Replay {
+    calls: VecDeque<Map<String, Value>>,
+}
+
+impl Replay {
+    fn mismatch(message: String) -> Error {
+        Error::new_with_source(ErrorDetail::System, Replayed::new(message))
+    }
+
+    // Take the next recorded call, which must match the one being made, including the data and
+    // register type of a copy
+    fn next(
+        &mut self,
+        op: &str,
+        clipboard: &str,
+        copied: Option<(&str, Option<Motion>)>,
+    ) -> clipboard::Result<Value> {
+        let call = self.calls.pop_front().ok_or_else(|| {
+            Self::mismatch(format!(
+                "unexpected {} of {}: no more recorded calls",
+                op, clipboard
+            ))
+        })?;
+        let recorded = |key| call.get(key).and_then(Value::as_str);
+        if recorded("op") != Some(op)
+            || recorded("clipboard") != Some(clipboard)
+            || copied.is_some_and(|(data, motion)| {
+                recorded("data") != Some(data) || recorded("regtype") != motion.map(Motion::regtype)
+            })
+        {
+            return Err(Self::mismatch(format!(
+                "expected recorded {} of {}, got {} of {}",
+                recorded("op").unwrap_or("?"),
+                recorded("clipboard").unwrap_or("?"),
+                op,
+                clipboard
+            )));
+        }
+
+        let result = call.get("result").cloned().unwrap_or_default();
+        match result.get("success") {
+            Some(Value::Bool(true)) => Ok(result),
+            // The top-level message is the error detail, which is reproduced by the source chain
+            _ => Err(Error::new_with_source(
+                ErrorDetail::System,
+                Replayed::from_json(result.get("source").unwrap_or(&result)),
+            )),
+        }
+    }
+}
+
+impl Backend for Replay {
+    fn copy(&mut self, dest: Dest, data: &str, motion: Option<Motion>) -> clipboard::Result<()> {
+        self.next("copy", dest.name(), Some((data, motion)))?;
+        Ok(())
+    }
+
+    fn paste(&mut self, source: Source) -> clipboard::Result<Data> {
+        let result = self.next("paste", source.name(), None)?;
+        Ok(Data {
+            data: result
+                .get("data")
+                .and_then(Value::as_str)
+                .unwrap_or("")
+                .into(),
+            mime: result.get("mime").and_then(Value::as_str).map(Into::into),
+            motion: result
+                .get("regtype")
+                .and_then(Value::as_str)
+                .and_then(Motion::from_regtype),
+        })
+    }
+}
+
+// Replay recorded requests against a fake backend, printing responses and checking them
+// against the recorded ones.  With `--serve`, requests are instead read from stdin in the
+// framing and encoding given by the options, and only the backend calls are replayed.
+pub fn replay(options: &Options, args: &[String]) -> Result<()> {
+    let (path, serve) = match args {
+        [path] => (path, false),
+        [flag, path] if flag == "--serve" => (path, true),
+        _ => return Err(usage_error("Usage: clipipe replay [--serve] FILE".into())),
+    };
+
+    let mut calls = VecDeque::new();
+    let mut requests = vec![];
+    for line in BufReader::new(File::open(path)?).lines() {
+        let entry: Map<String, Value> = serde_json::from_str(&line?)?;
+        if let Some(Value::Object(call)) = entry.get("backend") {
+            calls.push_back(call.clone());
+        } else if let Some(Value::String(request)) = entry.get("request") {
+            requests.push((request.clone(), entry.get("response").cloned()));
+        }
+    }
+
+    let mut clipipe = Clipipe::with_backend(options, Box::new(Replay { calls }), None);
+    let mut stdout = io::stdout().lock();
+    if serve {
+        let wire = Wire::new(options);
+        wire::set_max_request(options);
+        let (events, queue) = mpsc::channel();
+        crate::spawn_reader(events, wire, Box::new(io::stdin()));
+        crate::serve(&mut clipipe, wire, &mut stdout, &queue)?;
+        clipipe.flush();
+        return Ok(());
+    }
+    let mut stderr = io::stderr().lock();
+    let mut differ = 0;
+    for (n, (request, expected)) in requests.iter().enumerate() {
+        let res = serde_json::to_value(clipipe.handle(request))?;
+        writeln!(stdout, "{}", res)?;
+        if expected.as_ref() != Some(&res) {
+            differ += 1;
+            writeln!(
+                stderr,
+                "Request {}: expected {}, got {}",
+                n + 1,
+                expected.as_ref().unwrap_or(&Value::Null),
+                res
+            )?;
+        }
+    }
+
+    if differ > 0 {
+        return Err(format!(
+            "{} of {} responses differ from the recording",
+            differ,
+            requests.len()
+        )
+        .into());
+    }
+    Ok(())
+}
@@ -0,0 +1,103 @@
+// Neovim msgpack-RPC mode.  Calls of `clipipe_ACTION` methods map onto the request with that
+// action, with the fields of the optional map parameter, and are answered with the response.
+use serde_json::{Value, json};
+use std::io::{self, Read, Write};
+
+use crate::log::{self, Level};
+use crate::protocol::Response;
+
+const REQUEST: u64 = 0;
+const RESPONSE: u64 = 1;
+const NOTIFICATION: u64 = 2;
+
+// Prefix of our methods
+const PREFIX: &str = "clipipe_";
+
+// Sets `g:clipipe_channel` in Neovim, so plugins can find a clipipe that connected by itself
+const ANNOUNCE: &str = "vim.g.clipipe_channel = vim.api.nvim_get_chan_info(0).id";
+
+// Incoming message
+pub enum Incoming {
+    // Call of one of our methods: the request it maps to, and the message ID to respond to
+    // unless it's a notification
+    Call(Value, Option<u64>),
+    // Response to one of our own calls
+    Response,
+    // Malformed message, and the message ID to answer with an error if it has one
+    Invalid(Option<u64>),
+}
+
+// Interpret a message.  Parameters beyond the first, and any parameters of an oversized message
+// (which were dropped while reading), are ignored.
+pub fn parse(message: Value) -> Incoming {
+    let Value::Array(message) = message else {
+        return Incoming::Invalid(None);
+    };
+    let (id, method, params) = match message.first().and_then(Value::as_u64) {
+        Some(REQUEST) => match message.get(1).and_then(Value::as_u64) {
+            Some(id) => (Some(id), message.get(2), message.get(3)),
+            None => return Incoming::Invalid(None),
+        },
+        Some(NOTIFICATION) => (None, message.get(1), message.get(2)),
+        Some(RESPONSE) => {
+            if let Some(error) = message.get(2).filter(|error| !error.is_null()) {
+                let mut fields = serde_json::Map::new();
+                fields.insert("error".into(), error.clone());
+                log::log(Level::Warn, "Neovim call failed", fields);
+            }
+            return Incoming::Response;
+        }
+        _ => return Incoming::Invalid(None),
+    };
+    let Some(method) = method.and_then(Value::as_str) else {
+        return Incoming::Invalid(id);
+    };
+    // Unknown methods are reported by the request parser, like unknown actions
+    let action = method.strip_prefix(PREFIX).unwrap_or(method);
+    let mut request = match params.and_then(|params| params.get(0)) {
+        Some(Value::Object(fields)) => fields.clone(),
+        Some(_) => return Incoming::Invalid(id),
+        None => serde_json::Map::new(),
+    };
+    request.insert("action".into(), action.into());
+    Incoming::Call(request.into(), id)
+}
+
+// Response message for a call.  Failures are reported as errors in the `[type, message]` form
+// Neovim expects, with the whole error chain in the message.
+pub fn response(id: u64, res: &Response) -> io::Result<Value> {
+    if res.success {
+        return Ok(json!([RESPONSE, id, null, serde_json::to_value(res)?]));
+    }
+    let mut message = res.message.clone().unwrap_or_default();
+    let mut source = res.source.as_deref();
+    while let Some(error) = source {
+        message = format!("{}: {}", message, error.message);
+        source = error.source.as_deref();
+    }
+    Ok(json!([RESPONSE, id, [0, message], null]))
+}
+
+// Call announcing our channel, made once connected
+pub fn announce() -> Value {
+    json!([REQUEST, 0, "nvim_exec_lua", [ANNOUNCE, []]])
+}
+
+pub type Connection = (Box<dyn Read + Send>, Box<dyn Write>);
+
+// Connect to a Neovim server address, as in `$NVIM`: a Unix socket path, or `host:port`
+pub fn connect(address: &str) -> io::Result<Connection> {
+    #[cfg(unix)]
+    if address.contains('/') {
+        let stream = std::os::unix::net::UnixStream::connect(address)?;
+        return Ok((Box::new(stream.try_clone()?), Box::new(stream)));
+    }
+    if address.starts_with(r"\\") {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "named pipes aren't supported; listen on host:port instead",
+        ));
+    }
+    let stream = std::net::TcpStream::connect(address)?;
+    Ok((Box::new(stream.try_clone()?), Box::new(stream)))
+}
@@ -0,0 +1,115 @@
+// Transfer of large payloads through private temporary files instead of the request pipe
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::Result;
+use crate::clipboard::PayloadTooLarge;
+
+// Directory for spill files: the per-user runtime directory if there is one, since it's
+// private and not persistent
+fn dir() -> PathBuf {
+    #[cfg(target_os = "linux")]
+    if let Some(dir) = env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        return dir.into();
+    }
+    env::temp_dir()
+}
+
+// Is this the name of a spill file, `clipipe-PID-N.txt`?
+fn is_spill_name(name: &str) -> bool {
+    name.strip_prefix("clipipe-")
+        .and_then(|name| name.strip_suffix(".txt"))
+        .and_then(|name| name.split_once('-'))
+        .is_some_and(|(pid, n)| {
+            [pid, n]
+                .iter()
+                .all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+        })
+}
+
+// Is this a spill file in the spill directory?  Other paths are refused, so a request can't read
+// and remove arbitrary files.
+fn is_spill_path(path: &Path) -> bool {
+    let named = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(is_spill_name);
+    let in_dir = match (path.parent().map(fs::canonicalize), fs::canonicalize(dir())) {
+        (Some(Ok(parent)), Ok(dir)) => parent == dir,
+        _ => false,
+    };
+    named && in_dir
+}
+
+// Create a new file only we can read
+fn create() -> io::Result<(PathBuf, File)> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let name = format!(
+        "clipipe-{}-{}.txt",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let path = dir().join(name);
+    let mut options = File::options();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let file = options.open(&path)?;
+    Ok((path, file))
+}
+
+// Write pasted data to a new file, which the client removes after reading it
+pub fn write(data: &str) -> io::Result<PathBuf> {
+    let (path, mut file) = create()?;
+    if let Err(err) = file.write_all(data.as_bytes()) {
+        let _ = fs::remove_file(&path);
+        return Err(err);
+    }
+    Ok(path)
+}
+
+// Read data to copy from a spill file written by the client, up to the maximum size
+fn read(path: &Path, max: Option<usize>) -> Result<String> {
+    let mut options = File::options();
+    options.read(true);
+    #[cfg(target_os = "linux")]
+    std::os::unix::fs::OpenOptionsExt::custom_flags(&mut options, libc::O_NOFOLLOW);
+    let file = options.open(path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(format!("Not a spill file: {}", path.display()).into());
+    }
+    let max = max.unwrap_or(usize::MAX);
+    let too_large = |size| PayloadTooLarge {
+        size: Some(size),
+        max,
+    };
+    if metadata.len() > max as u64 {
+        return Err(too_large(metadata.len() as usize).into());
+    }
+    // The file could still grow
+    let mut data = String::new();
+    file.take((max as u64).saturating_add(1))
+        .read_to_string(&mut data)?;
+    if data.len() > max {
+        return Err(too_large(data.len()).into());
+    }
+    Ok(data)
+}
+
+// Read data to copy from a spill file written by the client, removing it.  The file must be
+// named like ours and in the same directory, and count against the maximum request size.
+pub fn take(path: &str, max: Option<usize>) -> Result<String> {
+    let path = Path::new(path);
+    // Checked before following links, so a link can't stand in for another file
+    let is_file = fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_file());
+    if !is_file || !is_spill_path(path) {
+        return Err(format!("Not a spill file: {}", path.display()).into());
+    }
+    let data = read(path, max);
+    let _ = fs::remove_file(path);
+    data
+}
@@ -0,0 +1,130 @@
+// Vim's selection formats, which carry the motion type of a register along with its text so
+// it's still linewise or blockwise when put in another editor.  They're X11 targets, and are
+// offered under the same names as Wayland MIME types.
+use crate::clipboard::{self, Motion};
+
+// Motion type, then the text
+pub const VIM_TEXT: &str = "_VIM_TEXT";
+// Motion type, the name of the encoding and a NUL, then the text
+pub const VIMENC_TEXT: &str = "_VIMENC_TEXT";
+
+// Vim's motion type codes.  MAUTO leaves it to the receiver to guess from the text.
+const MCHAR: u8 = 0;
+const MLINE: u8 = 1;
+const MBLOCK: u8 = 2;
+const MAUTO: u8 = 255;
+
+const ENCODING: &[u8] = b"utf-8";
+
+fn code(motion: Option<Motion>) -> u8 {
+    match motion {
+        Some(Motion::Char) => MCHAR,
+        Some(Motion::Line) => MLINE,
+        Some(Motion::Block) => MBLOCK,
+        None => MAUTO,
+    }
+}
+
+// `_VIM_TEXT` contents.  The text is assumed to be in Vim's 'encoding', normally UTF-8.
+pub fn vim_text(data: &str, motion: Option<Motion>) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len() + 1);
+    bytes.push(code(motion));
+    bytes.extend_from_slice(data.as_bytes());
+    bytes
+}
+
+// `_VIMENC_TEXT` contents
+pub fn vimenc_text(data: &str, motion: Option<Motion>) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len() + ENCODING.len() + 2);
+    bytes.push(code(motion));
+    bytes.extend_from_slice(ENCODING);
+    bytes.push(0);
+    bytes.extend_from_slice(data.as_bytes());
+    bytes
+}
+
+// Text and motion type of `_VIMENC_TEXT` contents.  Latin-1 is converted; other encodings are
+// taken as UTF-8.
+pub fn from_vimenc_text(mut bytes: Vec<u8>) -> (String, Option<Motion>) {
+    let motion = match bytes.first() {
+        Some(&MCHAR) => Some(Motion::Char),
+        Some(&MLINE) => Some(Motion::Line),
+        Some(&MBLOCK) => Some(Motion::Block),
+        _ => None,
+    };
+    // The encoding name ends at the first NUL after the motion type, which may itself be 0
+    let Some(end) = bytes
+        .iter()
+        .skip(1)
+        .position(|b| *b == 0)
+        .map(|end| end + 1)
+    else {
+        return (String::new(), None);
+    };
+    let latin1 = matches!(
+        bytes[1..end].to_ascii_lowercase().as_slice(),
+        b"latin1" | b"iso-8859-1" | b"iso-8859"
+    );
+    bytes.drain(..=end);
+    let data = match latin1 {
+        true => bytes.iter().map(|b| *b as char).collect(),
+        false => clipboard::text(bytes),
+    };
+    (data, motion)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vimenc_round_trip() {
+        for motion in [
+            Some(Motion::Char),
+            Some(Motion::Line),
+            Some(Motion::Block),
+            None,
+        ] {
+            for data in ["", "text", "two\nlines\n", "wide ✓ \0 nul"] {
+                let bytes = vimenc_text(data, motion);
+                assert_eq!(from_vimenc_text(bytes), (data.into(), motion));
+            }
+        }
+    }
+
+    #[test]
+    fn vimenc_latin1() {
+        let mut bytes = vec![MLINE];
+        bytes.extend_from_slice(b"latin1\0caf\xe9\n");
+        assert_eq!(
+            from_vimenc_text(bytes),
+            ("café\n".into(), Some(Motion::Line))
+        );
+        let mut bytes = vec![MCHAR];
+        bytes.extend_from_slice(b"ISO-8859-1\0\xff");
+        assert_eq!(from_vimenc_text(bytes), ("ÿ".into(), Some(Motion::Char)));
+    }
+
+    #[test]
+    fn vimenc_empty_encoding() {
+        // Taken as UTF-8
+        let mut bytes = vec![MBLOCK, 0];
+        bytes.extend_from_slice("ab\ncd ✓".as_bytes());
+        assert_eq!(
+            from_vimenc_text(bytes),
+            ("ab\ncd ✓".into(), Some(Motion::Block))
+        );
+    }
+
+    #[test]
+    fn vimenc_truncated() {
+        for bytes in [&b""[..], &[MCHAR], &[MLINE], b"\x01utf-8", b"\x00utf"] {
+            assert_eq!(from_vimenc_text(bytes.to_vec()), (String::new(), None));
+        }
+        // The header survived, but not the text
+        assert_eq!(
+            from_vimenc_text(b"\x01utf-8\0".to_vec()),
+            (String::new(), Some(Motion::Line))
+        );
+    }
+}
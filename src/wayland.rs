@@ -0,0 +1,308 @@
+// Wayland selection server.  A long-lived clipipe keeps one compositor connection and serves
+// its selections from a dispatch thread using the wlr data-control protocol, instead of
+// starting a new connection and serving thread for every copy.  Vim's formats are offered
+// as MIME types along with the text, to carry the motion type.
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use wayland_client::globals::{GlobalListContents, registry_queue_init};
+use wayland_client::protocol::wl_registry::WlRegistry;
+use wayland_client::protocol::wl_seat::WlSeat;
+use wayland_client::{Connection, Dispatch, Proxy, QueueHandle, event_created_child};
+use wayland_protocols_wlr::data_control::v1::client::{
+    zwlr_data_control_device_v1::{self, ZwlrDataControlDeviceV1},
+    zwlr_data_control_manager_v1::ZwlrDataControlManagerV1,
+    zwlr_data_control_offer_v1::ZwlrDataControlOfferV1,
+    zwlr_data_control_source_v1::{self, ZwlrDataControlSourceV1},
+};
+
+use crate::clipboard::{Error, ErrorDetail, Motion, Result};
+use crate::log::{self, Level};
+use crate::vim;
+
+// MIME types offered for copied text, most specific first
+pub const TEXT_MIME_TYPES: &[&str] = &[
+    "text/plain;charset=utf-8",
+    "text/plain",
+    "UTF8_STRING",
+    "STRING",
+    "TEXT",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Selection {
+    Regular,
+    Primary,
+}
+
+// Our current offer for a selection: one source per seat
+struct Offer {
+    sources: Vec<ZwlrDataControlSourceV1>,
+    data: Arc<str>,
+    motion: Option<Motion>,
+}
+
+#[derive(Default)]
+struct Offers {
+    regular: Option<Offer>,
+    primary: Option<Offer>,
+}
+
+impl Offers {
+    fn get(&mut self, selection: Selection) -> &mut Option<Offer> {
+        match selection {
+            Selection::Regular => &mut self.regular,
+            Selection::Primary => &mut self.primary,
+        }
+    }
+}
+
+type SharedOffers = Arc<Mutex<Offers>>;
+
+fn lock(offers: &SharedOffers) -> MutexGuard<'_, Offers> {
+    offers.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Data-control device for each seat, until the seat goes away
+type SharedDevices = Arc<Mutex<Vec<ZwlrDataControlDeviceV1>>>;
+
+fn lock_devices(devices: &SharedDevices) -> MutexGuard<'_, Vec<ZwlrDataControlDeviceV1>> {
+    devices.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// State of the dispatch thread
+struct State {
+    offers: SharedOffers,
+    devices: SharedDevices,
+}
+
+// Data attached to each source
+struct SourceData {
+    selection: Selection,
+    data: Arc<str>,
+    motion: Option<Motion>,
+}
+
+fn system<E: std::error::Error + 'static>(err: E) -> Error {
+    Error::new_with_source(ErrorDetail::System, err)
+}
+
+fn no_seats() -> Error {
+    system(io::Error::other("no seats"))
+}
+
+pub struct Server {
+    conn: Connection,
+    qh: QueueHandle<State>,
+    manager: ZwlrDataControlManagerV1,
+    devices: SharedDevices,
+    offers: SharedOffers,
+}
+
+impl Server {
+    // Connect to the compositor and start serving events
+    pub fn new() -> Result<Server> {
+        let conn = Connection::connect_to_env().map_err(system)?;
+        let (globals, mut queue) = registry_queue_init::<State>(&conn).map_err(system)?;
+        let qh = queue.handle();
+        // Version 2 is needed for the primary selection
+        let manager: ZwlrDataControlManagerV1 = globals.bind(&qh, 1..=2, ()).map_err(system)?;
+        let registry = globals.registry();
+        let devices = globals.contents().with_list(|list| {
+            list.iter()
+                .filter(|global| global.interface == WlSeat::interface().name)
+                .map(|global| {
+                    let seat: WlSeat = registry.bind(global.name, 1, &qh, ());
+                    manager.get_data_device(&seat, &qh, ())
+                })
+                .collect::<Vec<_>>()
+        });
+        if devices.is_empty() {
+            return Err(no_seats());
+        }
+
+        let offers = SharedOffers::default();
+        let devices = SharedDevices::new(Mutex::new(devices));
+        let mut state = State {
+            offers: offers.clone(),
+            devices: devices.clone(),
+        };
+        thread::spawn(move || {
+            loop {
+                if let Err(err) = queue.blocking_dispatch(&mut state) {
+                    log::error(Level::Error, "Wayland connection failed", &err);
+                    *lock(&state.offers) = Offers::default();
+                    break;
+                }
+            }
+        });
+
+        Ok(Server {
+            conn,
+            qh,
+            manager,
+            devices,
+            offers,
+        })
+    }
+
+    pub fn primary_supported(&self) -> bool {
+        self.manager.version() >= 2
+    }
+
+    // Replace our offer for a selection
+    pub fn copy(&self, selection: Selection, data: &str, motion: Option<Motion>) -> Result<()> {
+        let data: Arc<str> = data.into();
+        let devices = lock_devices(&self.devices);
+        if devices.is_empty() {
+            return Err(no_seats());
+        }
+        let sources = devices
+            .iter()
+            .map(|device| {
+                let source = self.manager.create_data_source(
+                    &self.qh,
+                    SourceData {
+                        selection,
+                        data: data.clone(),
+                        motion,
+                    },
+                );
+                for mime in [vim::VIMENC_TEXT, vim::VIM_TEXT]
+                    .iter()
+                    .chain(TEXT_MIME_TYPES)
+                {
+                    source.offer(mime.to_string());
+                }
+                match selection {
+                    Selection::Regular => device.set_selection(Some(&source)),
+                    Selection::Primary => device.set_primary_selection(Some(&source)),
+                }
+                source
+            })
+            .collect();
+        // The previous sources are cancelled by the compositor and destroyed when that arrives
+        *lock(&self.offers).get(selection) = Some(Offer {
+            sources,
+            data,
+            motion,
+        });
+        self.conn.flush().map_err(system)
+    }
+
+    // Contents of a selection and their motion type, if we still own it
+    pub fn owned(&self, selection: Selection) -> Option<(Arc<str>, Option<Motion>)> {
+        lock(&self.offers)
+            .get(selection)
+            .as_ref()
+            .map(|offer| (offer.data.clone(), offer.motion))
+    }
+}
+
+// Write selection contents to a requesting client without holding up the dispatch thread
+fn send(fd: OwnedFd, data: Arc<[u8]>) {
+    thread::spawn(move || {
+        // SAFETY: plain system calls on a valid file descriptor
+        unsafe {
+            let flags = libc::fcntl(fd.as_raw_fd(), libc::F_GETFL);
+            libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags & !libc::O_NONBLOCK);
+        }
+        if let Err(err) = File::from(fd).write_all(&data) {
+            log::error(Level::Warn, "couldn't send selection", &err);
+        }
+    });
+}
+
+impl Dispatch<ZwlrDataControlSourceV1, SourceData> for State {
+    fn event(
+        state: &mut Self,
+        source: &ZwlrDataControlSourceV1,
+        event: zwlr_data_control_source_v1::Event,
+        data: &SourceData,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_data_control_source_v1::Event::Send { mime_type, fd } => {
+                match mime_type.as_str() {
+                    vim::VIMENC_TEXT => send(fd, vim::vimenc_text(&data.data, data.motion).into()),
+                    vim::VIM_TEXT => send(fd, vim::vim_text(&data.data, data.motion).into()),
+                    mime if TEXT_MIME_TYPES.contains(&mime) => send(fd, data.data.clone().into()),
+                    _ => (),
+                }
+            }
+            zwlr_data_control_source_v1::Event::Cancelled => {
+                source.destroy();
+                let mut offers = lock(&state.offers);
+                let offer = offers.get(data.selection);
+                if let Some(current) = offer {
+                    current.sources.retain(|s| s != source);
+                }
+                if offer
+                    .as_ref()
+                    .is_some_and(|current| current.sources.is_empty())
+                {
+                    *offer = None;
+                    log::log(Level::Debug, "selection taken over", Default::default());
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Dispatch<ZwlrDataControlDeviceV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        device: &ZwlrDataControlDeviceV1,
+        event: zwlr_data_control_device_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            // Other clients' offers are read through wl-clipboard-rs, so don't track them
+            zwlr_data_control_device_v1::Event::DataOffer { id } => id.destroy(),
+            // The seat is gone, so stop copying to it
+            zwlr_data_control_device_v1::Event::Finished => {
+                let mut devices = lock_devices(&state.devices);
+                devices.retain(|d| d != device);
+                device.destroy();
+            }
+            _ => (),
+        }
+    }
+
+    event_created_child!(State, ZwlrDataControlDeviceV1, [
+        zwlr_data_control_device_v1::EVT_DATA_OFFER_OPCODE => (ZwlrDataControlOfferV1, ()),
+    ]);
+}
+
+// Events for the remaining objects aren't needed
+macro_rules! ignore_events {
+    ($($iface:ty: $udata:ty),*) => {
+        $(
+            impl Dispatch<$iface, $udata> for State {
+                fn event(
+                    _state: &mut Self,
+                    _proxy: &$iface,
+                    _event: <$iface as Proxy>::Event,
+                    _data: &$udata,
+                    _conn: &Connection,
+                    _qh: &QueueHandle<Self>,
+                ) {
+                }
+            }
+        )*
+    };
+}
+
+ignore_events!(
+    WlRegistry: GlobalListContents,
+    WlSeat: (),
+    ZwlrDataControlManagerV1: (),
+    ZwlrDataControlOfferV1: ()
+);
@@ -1,9 +1,9 @@
-use std::env;
 use std::thread;
 use std::time::Duration;
 
-use crate::clipboard::{self, Data, Dest, Error, ErrorDetail, Result, Source};
-use clipboard_win::{formats, get, set, Clipboard, ErrorCode};
+use crate::clipboard::{self, Data, Dest, Error, ErrorDetail, Motion, Result, Source};
+use crate::options::{BackendKind, LineEndings, Options};
+use clipboard_win::{Clipboard, ErrorCode, formats, get, set};
 
 // ErrorCode doesn't implement std::error::Error for some reason, so wrap it
 #[derive(Debug)]
@@ -25,16 +25,22 @@ impl From<ErrorCode> for Error {
 
 pub struct Backend {
     convert_line_endings: bool,
+    // Lock held across a batch
+    lock: Option<Clipboard>,
 }
 
 impl Backend {
-    pub fn new() -> Result<Backend> {
+    pub fn new(options: &Options) -> Result<Backend> {
         Ok(Backend {
-            // FIXME: not the best way to plumb this setting through
-            convert_line_endings: !env::args().any(|arg| arg == "--keep-line-endings"),
+            convert_line_endings: options.line_endings == LineEndings::Convert,
+            lock: None,
         })
     }
 
+    pub fn kind(&self) -> BackendKind {
+        BackendKind::Windows
+    }
+
     // Get clipboard lock guard.
     //
     // The Windows clipboard has to be globally locked to be accessed, with contention resulting in
@@ -59,8 +65,16 @@ impl Backend {
         }
     }
 
-    fn get() -> Result<String> {
-        let _cb = Self::clipboard()?;
+    // Lock the clipboard for one operation, unless it's already locked
+    fn guard(&self) -> Result<Option<Clipboard>> {
+        match self.lock {
+            Some(_) => Ok(None),
+            None => Self::clipboard().map(Some),
+        }
+    }
+
+    fn get(&self) -> Result<String> {
+        let _cb = self.guard()?;
         Ok(match get(formats::Unicode) {
             Ok(data) => data,
             // FIXME: magic constants
@@ -69,30 +83,46 @@ impl Backend {
         })
     }
 
-    fn set(data: &str) -> Result<()> {
-        let _cb = Self::clipboard()?;
+    fn set(&self, data: &str) -> Result<()> {
+        let _cb = self.guard()?;
         Ok(set(formats::Unicode, data)?)
     }
 }
 
 impl clipboard::Backend for Backend {
-    fn copy(&mut self, _dest: Dest, data: &str) -> Result<()> {
+    fn copy(&mut self, _dest: Dest, data: &str, _motion: Option<Motion>) -> Result<()> {
         if self.convert_line_endings {
             let data = data.replace("\n", "\r\n");
-            Self::set(&data)
+            self.set(&data)
         } else {
-            Self::set(data)
+            self.set(data)
         }
     }
 
     fn paste(&mut self, _src: Source) -> Result<Data> {
-        let mut data = Self::get()?;
+        let mut data = self.get()?;
         if self.convert_line_endings {
             data = data.replace("\r\n", "\n");
         }
         Ok(Data {
             data,
             mime: None,
+            motion: None,
         })
     }
+
+    fn configure(&mut self, options: &Options) {
+        self.convert_line_endings = options.line_endings == LineEndings::Convert;
+    }
+
+    fn lock(&mut self) -> Result<()> {
+        if self.lock.is_none() {
+            self.lock = Some(Self::clipboard()?);
+        }
+        Ok(())
+    }
+
+    fn unlock(&mut self) {
+        self.lock = None;
+    }
 }
@@ -0,0 +1,285 @@
+// Reading requests and writing responses in the framing and encoding chosen at startup
+use serde_json::{Map, Value};
+use std::io::{self, BufRead, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::Event;
+use crate::log::{self, Level};
+use crate::msgpack;
+use crate::options::{Encoding, Framing, Options};
+use crate::protocol::Response;
+use crate::rpc::{self, Incoming};
+
+// Request as read, before parsing
+pub enum Message {
+    Json(String),
+    // Decoded MessagePack
+    Msgpack(Value),
+    // Call through an editor channel: the request, and the message ID to respond to, if any
+    Call(Value, Option<u64>),
+}
+
+// Longest header line accepted with `Content-Length` framing
+const MAX_HEADER: usize = 4096;
+
+// Maximum request size, shared with the reader thread so a reload takes effect
+static MAX_REQUEST: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+pub fn set_max_request(options: &Options) {
+    MAX_REQUEST.store(options.max_request.unwrap_or(usize::MAX), Ordering::Relaxed);
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Editor channel protocols, which replace the framing and encoding
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    Plain,
+    // Neovim msgpack-RPC
+    Rpc,
+    // Vim channel in JSON mode: `[id, message]` arrays, one per line
+    Vim,
+}
+
+#[derive(Clone, Copy)]
+pub struct Wire {
+    pub framing: Framing,
+    pub encoding: Encoding,
+    pub mode: Mode,
+}
+
+impl Default for Wire {
+    fn default() -> Wire {
+        Wire {
+            framing: Framing::Lines,
+            encoding: Encoding::Json,
+            mode: Mode::Plain,
+        }
+    }
+}
+
+impl Wire {
+    pub fn new(options: &Options) -> Wire {
+        Wire {
+            framing: options.framing,
+            encoding: options.encoding,
+            mode: if options.rpc || options.nvim.is_some() {
+                Mode::Rpc
+            } else if options.vim {
+                Mode::Vim
+            } else {
+                Mode::Plain
+            },
+        }
+    }
+
+    // Read a request, or None at end of input.  A request over the maximum size is skipped
+    // without buffering it.
+    pub fn read(self, input: &mut impl BufRead) -> io::Result<Option<Event>> {
+        let max = MAX_REQUEST.load(Ordering::Relaxed);
+        match self.mode {
+            Mode::Plain => (),
+            Mode::Rpc => return read_rpc(input, max),
+            Mode::Vim => return read_vim(input, max),
+        }
+        match (self.framing, self.encoding) {
+            (Framing::Lines, Encoding::Json) => read_line(input, max),
+            // MessagePack values delimit themselves
+            (Framing::Lines, Encoding::Msgpack) => read_msgpack(input, max),
+            (Framing::ContentLength, encoding) => read_framed(input, max, encoding),
+        }
+    }
+
+    // Write a response.  With msgpack-RPC, only calls with a message ID get one; on a Vim
+    // channel, others go to the channel callback with ID 0.
+    pub fn write(self, output: &mut impl Write, res: &Response, id: Option<u64>) -> io::Result<()> {
+        match self.mode {
+            Mode::Plain => (),
+            Mode::Rpc => {
+                return match id {
+                    Some(id) => send(output, &rpc::response(id, res)?),
+                    None => Ok(()),
+                };
+            }
+            Mode::Vim => {
+                serde_json::to_writer(&mut *output, &(id.unwrap_or(0), res))?;
+                writeln!(output)?;
+                return output.flush();
+            }
+        }
+        let mut body = match self.encoding {
+            Encoding::Json => serde_json::to_vec(res)?,
+            Encoding::Msgpack => {
+                let mut body = vec![];
+                msgpack::encode(&mut body, &serde_json::to_value(res)?);
+                body
+            }
+        };
+        match (self.framing, self.encoding) {
+            (Framing::Lines, Encoding::Json) => body.push(b'\n'),
+            (Framing::Lines, Encoding::Msgpack) => (),
+            (Framing::ContentLength, _) => {
+                write!(output, "Content-Length: {}\r\n\r\n", body.len())?;
+            }
+        }
+        output.write_all(&body)?;
+        output.flush()
+    }
+}
+
+// Write a MessagePack value
+pub fn send(output: &mut impl Write, value: &Value) -> io::Result<()> {
+    let mut body = vec![];
+    msgpack::encode(&mut body, value);
+    output.write_all(&body)?;
+    output.flush()
+}
+
+fn read_line(input: &mut impl BufRead, max: usize) -> io::Result<Option<Event>> {
+    let mut line = vec![];
+    let mut size = 0;
+    loop {
+        let buf = input.fill_buf()?;
+        if buf.is_empty() {
+            if size == 0 {
+                return Ok(None);
+            }
+            break;
+        }
+        let (chunk, newline) = match buf.iter().position(|b| *b == b'\n') {
+            Some(n) => (&buf[..n], true),
+            None => (buf, false),
+        };
+        size += chunk.len();
+        if size <= max {
+            line.extend_from_slice(chunk);
+        }
+        let used = chunk.len() + newline as usize;
+        input.consume(used);
+        if newline {
+            break;
+        }
+    }
+    if size > max {
+        return Ok(Some(Event::Oversized(size, None)));
+    }
+    // Like BufRead::lines
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    decode(line, Encoding::Json).map(Some)
+}
+
+fn read_msgpack(input: &mut impl BufRead, max: usize) -> io::Result<Option<Event>> {
+    Ok(msgpack::read(input, max)?.map(|(value, size)| {
+        if size > max {
+            Event::Oversized(size, None)
+        } else {
+            Event::Request(Some(Message::Msgpack(value)))
+        }
+    }))
+}
+
+// Read the next call, skipping responses to our own calls
+fn read_rpc(input: &mut impl BufRead, max: usize) -> io::Result<Option<Event>> {
+    while let Some((value, size)) = msgpack::read(input, max)? {
+        match rpc::parse(value) {
+            Incoming::Call(_, id) if size > max => return Ok(Some(Event::Oversized(size, id))),
+            Incoming::Call(request, id) => {
+                return Ok(Some(Event::Request(Some(Message::Call(request, id)))));
+            }
+            Incoming::Response => (),
+            Incoming::Invalid(Some(id)) => return Ok(Some(Event::Invalid(id))),
+            // Nothing to answer, but the stream is still in sync
+            Incoming::Invalid(None) => {
+                log::log(
+                    Level::Warn,
+                    "ignored invalid msgpack-RPC message",
+                    Map::new(),
+                );
+            }
+        }
+    }
+    Ok(None)
+}
+
+// Read a Vim channel message.  Lines that aren't `[id, message]` are taken as plain requests.
+fn read_vim(input: &mut impl BufRead, max: usize) -> io::Result<Option<Event>> {
+    let event = read_line(input, max)?;
+    let Some(Event::Request(Some(Message::Json(line)))) = event else {
+        return Ok(event);
+    };
+    Ok(Some(Event::Request(Some(
+        match serde_json::from_str::<(u64, Value)>(&line) {
+            Ok((id, message)) => Message::Call(message, Some(id)),
+            Err(_) => Message::Json(line),
+        },
+    ))))
+}
+
+// Read a message with `Content-Length` framing: header lines, a blank line, then the body
+fn read_framed(
+    input: &mut impl BufRead,
+    max: usize,
+    encoding: Encoding,
+) -> io::Result<Option<Event>> {
+    let mut length = None;
+    let mut header = String::new();
+    loop {
+        header.clear();
+        let read = io::Read::take(&mut *input, MAX_HEADER as u64).read_line(&mut header)?;
+        if read == 0 {
+            return match length {
+                None if header.is_empty() => Ok(None),
+                _ => Err(io::ErrorKind::UnexpectedEof.into()),
+            };
+        }
+        if read == MAX_HEADER && !header.ends_with('\n') {
+            return Err(invalid("Header line too long".into()));
+        }
+        let header = header.trim_end_matches(['\r', '\n']);
+        if header.is_empty() {
+            break;
+        }
+        // Other headers, such as `Content-Type`, don't matter
+        if let Some((name, value)) = header.split_once(':')
+            && name.trim().eq_ignore_ascii_case("content-length")
+        {
+            let value = value.trim();
+            length = Some(
+                value
+                    .parse::<usize>()
+                    .map_err(|_| invalid(format!("Invalid Content-Length: {}", value)))?,
+            );
+        }
+    }
+    let length = length.ok_or_else(|| invalid("Message without Content-Length".into()))?;
+    if length > max {
+        io::copy(&mut io::Read::take(input, length as u64), &mut io::sink())?;
+        return Ok(Some(Event::Oversized(length, None)));
+    }
+    // The length isn't trusted with an allocation, since there may be no maximum
+    let mut body = vec![];
+    input.take(length as u64).read_to_end(&mut body)?;
+    if body.len() != length {
+        return Err(invalid("Message shorter than its Content-Length".into()));
+    }
+    decode(body, encoding).map(Some)
+}
+
+// Decode a complete message
+fn decode(body: Vec<u8>, encoding: Encoding) -> io::Result<Event> {
+    let message = match encoding {
+        Encoding::Json => Message::Json(
+            String::from_utf8(body)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+        ),
+        Encoding::Msgpack => match msgpack::read(&mut body.as_slice(), usize::MAX)? {
+            Some((value, _)) => Message::Msgpack(value),
+            None => return Err(invalid("Empty message".into())),
+        },
+    };
+    Ok(Event::Request(Some(message)))
+}
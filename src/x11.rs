@@ -0,0 +1,342 @@
+// X11 selection server.  Serves copied text from a thread with its own connection, offering
+// Vim's targets with the motion type alongside the usual text targets, which x11-clipboard's
+// single target per selection can't do.
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use x11rb::connection::{Connection as _, RequestConnection as _};
+use x11rb::errors::ConnectionError;
+use x11rb::protocol::Event;
+use x11rb::protocol::xproto::{
+    Atom, AtomEnum, ChangeWindowAttributesAux, ConnectionExt as _, CreateWindowAux, EventMask,
+    PropMode, Property, PropertyNotifyEvent, SELECTION_NOTIFY_EVENT, SelectionNotifyEvent,
+    SelectionRequestEvent, Window, WindowClass,
+};
+use x11rb::rust_connection::RustConnection;
+use x11rb::wrapper::ConnectionExt as _;
+use x11rb::{COPY_DEPTH_FROM_PARENT, CURRENT_TIME, NONE};
+
+use crate::clipboard::{Error, ErrorDetail, Motion, Result};
+use crate::log::{self, Level};
+use crate::vim;
+
+// Our current offer for a selection
+struct Offer {
+    data: Arc<str>,
+    motion: Option<Motion>,
+}
+
+type SharedOffers = Arc<Mutex<HashMap<Atom, Offer>>>;
+
+fn lock(offers: &SharedOffers) -> MutexGuard<'_, HashMap<Atom, Offer>> {
+    offers.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn system<E: std::error::Error + 'static>(err: E) -> Error {
+    Error::new_with_source(ErrorDetail::System, err)
+}
+
+#[derive(Clone, Copy)]
+struct Atoms {
+    targets: Atom,
+    incr: Atom,
+    utf8_string: Atom,
+    text: Atom,
+    text_plain: Atom,
+    text_plain_utf8: Atom,
+    vim_text: Atom,
+    vimenc_text: Atom,
+}
+
+impl Atoms {
+    fn intern(conn: &RustConnection) -> Result<Atoms> {
+        let atom = |name: &str| -> Result<Atom> {
+            Ok(conn
+                .intern_atom(false, name.as_bytes())
+                .map_err(system)?
+                .reply()
+                .map_err(system)?
+                .atom)
+        };
+        Ok(Atoms {
+            targets: atom("TARGETS")?,
+            incr: atom("INCR")?,
+            utf8_string: atom("UTF8_STRING")?,
+            text: atom("TEXT")?,
+            text_plain: atom("text/plain")?,
+            text_plain_utf8: atom("text/plain;charset=utf-8")?,
+            vim_text: atom(vim::VIM_TEXT)?,
+            vimenc_text: atom(vim::VIMENC_TEXT)?,
+        })
+    }
+
+    // Targets we can convert to, most specific first
+    fn offered(&self) -> [Atom; 8] {
+        [
+            self.targets,
+            self.vimenc_text,
+            self.vim_text,
+            self.utf8_string,
+            self.text_plain_utf8,
+            self.text_plain,
+            self.text,
+            AtomEnum::STRING.into(),
+        ]
+    }
+}
+
+// Conversion too large for one request, sent in chunks as the requestor deletes the property
+struct Transfer {
+    ty: Atom,
+    value: Vec<u8>,
+    pos: usize,
+}
+
+// State of the serving thread
+struct State {
+    conn: Arc<RustConnection>,
+    window: Window,
+    atoms: Atoms,
+    offers: SharedOffers,
+    // Incremental transfers by requestor and property
+    transfers: HashMap<(Window, Atom), Transfer>,
+    // Largest property we set at once
+    max: usize,
+}
+
+impl State {
+    fn serve(mut self) {
+        loop {
+            let event = match self.conn.wait_for_event() {
+                Ok(event) => event,
+                Err(err) => {
+                    log::error(Level::Error, "X11 connection failed", &err);
+                    lock(&self.offers).clear();
+                    break;
+                }
+            };
+            let res = match event {
+                Event::SelectionRequest(event) => self.respond(event),
+                Event::SelectionClear(event) => self.clear(event.selection),
+                Event::PropertyNotify(event) if event.state == Property::DELETE => {
+                    self.send_chunk(event)
+                }
+                _ => Ok(()),
+            };
+            if let Err(err) = res.and_then(|_| self.conn.flush()) {
+                log::error(Level::Warn, "couldn't serve selection", &err);
+            }
+        }
+    }
+
+    fn respond(
+        &mut self,
+        event: SelectionRequestEvent,
+    ) -> std::result::Result<(), ConnectionError> {
+        // Obsolete clients don't name a property
+        let property = match event.property {
+            NONE => event.target,
+            property => property,
+        };
+        let offer = lock(&self.offers)
+            .get(&event.selection)
+            .map(|offer| (offer.data.clone(), offer.motion));
+        let converted = match offer {
+            Some((data, motion)) => {
+                self.convert(event.requestor, property, event.target, &data, motion)?
+            }
+            None => false,
+        };
+        self.conn.send_event(
+            false,
+            event.requestor,
+            EventMask::NO_EVENT,
+            SelectionNotifyEvent {
+                response_type: SELECTION_NOTIFY_EVENT,
+                sequence: 0,
+                time: event.time,
+                requestor: event.requestor,
+                selection: event.selection,
+                target: event.target,
+                property: if converted { property } else { NONE },
+            },
+        )?;
+        Ok(())
+    }
+
+    // Set the requested property, returning whether the target is supported
+    fn convert(
+        &mut self,
+        requestor: Window,
+        property: Atom,
+        target: Atom,
+        data: &str,
+        motion: Option<Motion>,
+    ) -> std::result::Result<bool, ConnectionError> {
+        let atoms = self.atoms;
+        let (ty, value) = if target == atoms.targets {
+            self.conn.change_property32(
+                PropMode::REPLACE,
+                requestor,
+                property,
+                AtomEnum::ATOM,
+                &atoms.offered(),
+            )?;
+            return Ok(true);
+        } else if target == atoms.vimenc_text {
+            (target, vim::vimenc_text(data, motion))
+        } else if target == atoms.vim_text {
+            (target, vim::vim_text(data, motion))
+        } else if target == atoms.text_plain || target == atoms.text_plain_utf8 {
+            (target, data.as_bytes().to_vec())
+        } else if atoms.offered().contains(&target) {
+            // Like x11-clipboard did, send UTF-8 for the older text targets too
+            (atoms.utf8_string, data.as_bytes().to_vec())
+        } else {
+            return Ok(false);
+        };
+        if value.len() <= self.max {
+            self.conn
+                .change_property8(PropMode::REPLACE, requestor, property, ty, &value)?;
+        } else {
+            self.conn.change_window_attributes(
+                requestor,
+                &ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE),
+            )?;
+            self.conn.change_property32(
+                PropMode::REPLACE,
+                requestor,
+                property,
+                atoms.incr,
+                &[value.len() as u32],
+            )?;
+            self.transfers
+                .insert((requestor, property), Transfer { ty, value, pos: 0 });
+        }
+        Ok(true)
+    }
+
+    // Send the next chunk of an incremental transfer, ending with an empty one
+    fn send_chunk(
+        &mut self,
+        event: PropertyNotifyEvent,
+    ) -> std::result::Result<(), ConnectionError> {
+        let key = (event.window, event.atom);
+        let Some(transfer) = self.transfers.get_mut(&key) else {
+            return Ok(());
+        };
+        let len = (transfer.value.len() - transfer.pos).min(self.max);
+        self.conn.change_property8(
+            PropMode::REPLACE,
+            event.window,
+            event.atom,
+            transfer.ty,
+            &transfer.value[transfer.pos..][..len],
+        )?;
+        transfer.pos += len;
+        if len == 0 {
+            self.transfers.remove(&key);
+        }
+        Ok(())
+    }
+
+    // Drop our offer once another client owns the selection.  A clear can arrive after we took
+    // the selection back, so check.
+    fn clear(&mut self, selection: Atom) -> std::result::Result<(), ConnectionError> {
+        let owner = match self.conn.get_selection_owner(selection)?.reply() {
+            Ok(reply) => reply.owner,
+            Err(_) => NONE,
+        };
+        if owner != self.window && lock(&self.offers).remove(&selection).is_some() {
+            log::log(Level::Debug, "selection taken over", Default::default());
+        }
+        Ok(())
+    }
+}
+
+pub struct Server {
+    conn: Arc<RustConnection>,
+    // Window owning our selections
+    pub window: Window,
+    offers: SharedOffers,
+}
+
+impl Server {
+    // Connect to the display and start serving events
+    pub fn new() -> Result<Server> {
+        let (conn, screen) = RustConnection::connect(None).map_err(system)?;
+        let window = conn.generate_id().map_err(system)?;
+        let screen = &conn.setup().roots[screen];
+        conn.create_window(
+            COPY_DEPTH_FROM_PARENT,
+            window,
+            screen.root,
+            0,
+            0,
+            1,
+            1,
+            0,
+            WindowClass::INPUT_OUTPUT,
+            screen.root_visual,
+            &CreateWindowAux::new(),
+        )
+        .map_err(system)?
+        .check()
+        .map_err(system)?;
+        let atoms = Atoms::intern(&conn)?;
+        // Leave room for the request header
+        let max = conn.maximum_request_bytes().saturating_sub(32);
+
+        let conn = Arc::new(conn);
+        let offers = SharedOffers::default();
+        let state = State {
+            conn: conn.clone(),
+            window,
+            atoms,
+            offers: offers.clone(),
+            transfers: HashMap::new(),
+            max,
+        };
+        thread::spawn(move || state.serve());
+
+        Ok(Server {
+            conn,
+            window,
+            offers,
+        })
+    }
+
+    // Take a selection and serve the data from it
+    pub fn copy(&self, selection: Atom, data: &str, motion: Option<Motion>) -> Result<()> {
+        let offer = Offer {
+            data: data.into(),
+            motion,
+        };
+        lock(&self.offers).insert(selection, offer);
+        self.conn
+            .set_selection_owner(self.window, selection, CURRENT_TIME)
+            .map_err(system)?;
+        let owner = self
+            .conn
+            .get_selection_owner(selection)
+            .map_err(system)?
+            .reply()
+            .map_err(system)?
+            .owner;
+        if owner != self.window {
+            lock(&self.offers).remove(&selection);
+            return Err(system(io::Error::other("couldn't take the selection")));
+        }
+        Ok(())
+    }
+
+    // Data and motion type we're serving from a selection, unless another client has taken it
+    // and we've been told so
+    pub fn owned(&self, selection: Atom) -> Option<(Arc<str>, Option<Motion>)> {
+        lock(&self.offers)
+            .get(&selection)
+            .map(|offer| (offer.data.clone(), offer.motion))
+    }
+}
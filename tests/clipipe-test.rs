@@ -1,9 +1,20 @@
 use serde_json::{json, Value};
 use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::process::{Child, Command, Stdio};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
 use std::str::FromStr;
 use std::sync::{Mutex, MutexGuard};
 
+#[cfg(target_os = "linux")]
+#[path = "../src/msgpack.rs"]
+mod msgpack;
+
+#[allow(dead_code)]
+#[path = "../src/protocol.rs"]
+mod protocol;
+
+use protocol::{ErrorInfo, Response};
+
 struct Clipipe<I, O> {
     child: Child,
     input: I,
@@ -43,9 +54,8 @@ impl Copy for DisplayServer {}
 
 static TEST_MUTEX: Mutex<()> = Mutex::new(());
 
-fn spawn(server: DisplayServer) -> Clipipe<impl BufRead, impl Write> {
-    let mut cmd = Command::new(env!("CARGO_BIN_EXE_clipipe"));
-    cmd.stdout(Stdio::piped()).stdin(Stdio::piped());
+fn command(program: impl AsRef<std::ffi::OsStr>, server: DisplayServer) -> Command {
+    let mut cmd = Command::new(program);
     match server {
         #[cfg(target_os = "linux")]
         DisplayServer::Wayland => {
@@ -58,6 +68,12 @@ fn spawn(server: DisplayServer) -> Clipipe<impl BufRead, impl Write> {
         #[cfg(target_os = "windows")]
         DisplayServer::Windows => (),
     };
+    cmd
+}
+
+fn spawn(server: DisplayServer) -> Clipipe<impl BufRead, impl Write> {
+    let mut cmd = command(env!("CARGO_BIN_EXE_clipipe"), server);
+    cmd.stdout(Stdio::piped()).stdin(Stdio::piped());
 
     let mut child = cmd.spawn().expect("Couldn't run clipipe");
     let input = BufReader::new(child.stdout.take().unwrap());
@@ -70,6 +86,67 @@ fn spawn(server: DisplayServer) -> Clipipe<impl BufRead, impl Write> {
     }
 }
 
+// Get path to clipipe under the name of another tool
+fn personality(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("personalities");
+    std::fs::create_dir_all(&dir).expect("Couldn't create personality directory");
+    let path = dir.join(name).with_extension(std::env::consts::EXE_EXTENSION);
+    if !path.exists() {
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(env!("CARGO_BIN_EXE_clipipe"), &path)
+            .expect("Couldn't create symlink");
+        #[cfg(windows)]
+        std::fs::copy(env!("CARGO_BIN_EXE_clipipe"), &path).expect("Couldn't copy binary");
+    }
+    path
+}
+
+// Write a recording for `clipipe replay`
+fn recording(name: &str, entries: &[Value]) -> PathBuf {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let lines: Vec<String> = entries.iter().map(Value::to_string).collect();
+    std::fs::write(&path, lines.join("\n")).expect("Couldn't write recording");
+    path
+}
+
+// Run `clipipe replay` on a recording with the given options
+fn replay(args: &[&str], path: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_clipipe"))
+        .args(args)
+        .arg("replay")
+        .arg(path)
+        // Spilled files go with the other test files
+        .env("XDG_RUNTIME_DIR", env!("CARGO_TARGET_TMPDIR"))
+        .output()
+        .expect("Couldn't run clipipe")
+}
+
+// Responses printed by `clipipe replay`
+fn responses(output: &Output) -> Vec<Response<'_>> {
+    std::str::from_utf8(&output.stdout)
+        .expect("Invalid UTF-8")
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("Invalid response"))
+        .collect()
+}
+
+// Run `clipipe replay --serve` on a recording with the given options, feeding it the input
+fn replay_serve(args: &[&str], path: &Path, input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_clipipe"))
+        .args(args)
+        .args(["replay", "--serve"])
+        .arg(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Couldn't run clipipe");
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(input).expect("Couldn't write request");
+    drop(stdin);
+    child.wait_with_output().expect("Couldn't wait for clipipe")
+}
+
 mod tests {
     use super::*;
     use rstest::rstest;
@@ -111,4 +188,782 @@ mod tests {
             assert_eq!(mime, "text/plain")
         }
     }
+
+    #[apply(template::display)]
+    fn xclip_personality(#[case] server: DisplayServer) {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        let xclip = personality("xclip");
+        let data = format!("xclip {:?}", server);
+
+        let mut child = command(&xclip, server)
+            .args(["-selection", "clipboard"])
+            .stdin(Stdio::piped())
+            .spawn()
+            .expect("Couldn't run xclip");
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(data.as_bytes())
+            .expect("Couldn't write to xclip");
+        assert!(child.wait().expect("Couldn't wait for xclip").success());
+
+        let output = command(&xclip, server)
+            .args(["-sel", "c", "-o"])
+            .output()
+            .expect("Couldn't run xclip");
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), data);
+    }
+
+    #[test]
+    fn replay_session() {
+        let copy = json!({"action": "copy", "clipboard": "clipboard", "data": "replayed"});
+        let paste = json!({"action": "paste", "clipboard": "primary"});
+        let path = recording(
+            "replay.jsonl",
+            &[
+                json!({"version": "0.0.0", "backend": "test"}),
+                json!({"backend": {"op": "copy", "clipboard": "clipboard", "data": "replayed",
+                       "result": {"success": true}}}),
+                json!({"request": copy.to_string(), "response": {"success": true}}),
+                json!({"backend": {"op": "paste", "clipboard": "primary",
+                       "result": {"success": false, "message": "system error",
+                                  "source": {"message": "timed out"}}}}),
+                json!({"request": paste.to_string(),
+                       "response": {"success": false, "message": "system error",
+                                    "source": {"message": "timed out"}}}),
+                json!({"request": paste.to_string(), "response": {"success": true}}),
+            ],
+        );
+
+        let output = replay(&[], &path);
+        let responses = responses(&output);
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0], Response::ok());
+        let source = ErrorInfo {
+            message: "timed out".into(),
+            ..Default::default()
+        };
+        assert_eq!(responses[1].source, Some(Box::new(source)));
+        // Recording has no backend call left for the last paste
+        assert!(!responses[2].success);
+
+        assert_eq!(output.status.code(), Some(1));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("Request 3"), "{}", stderr);
+        assert!(stderr.contains("1 of 3 responses differ"), "{}", stderr);
+    }
+
+    #[test]
+    fn coalesce() {
+        let copy = |data| json!({"action": "copy", "clipboard": "primary", "data": data});
+        let paste = json!({"action": "paste", "clipboard": "primary"});
+        let path = recording(
+            "coalesce.jsonl",
+            &[
+                json!({"version": "0.0.0", "backend": "test"}),
+                json!({"request": copy("first").to_string(), "response": {"success": true}}),
+                json!({"request": copy("second").to_string(), "response": {"success": true}}),
+                // Only the latest copy reaches the backend, before the paste reads it back
+                json!({"backend": {"op": "copy", "clipboard": "primary", "data": "second",
+                       "result": {"success": true}}}),
+                json!({"backend": {"op": "paste", "clipboard": "primary",
+                       "result": {"success": true, "data": "second"}}}),
+                json!({"request": paste.to_string(), "response": {"success": true}}),
+            ],
+        );
+
+        let output = replay(&["--coalesce", "1000"], &path);
+        let responses = responses(&output);
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0], Response::ok());
+        assert_eq!(responses[1], Response::ok());
+        assert_eq!(responses[2].data.as_deref(), Some("second"));
+    }
+
+    #[test]
+    fn max_paste() {
+        let paste = json!({"action": "paste", "clipboard": "primary"});
+        let path = recording(
+            "max_paste.jsonl",
+            &[
+                json!({"version": "0.0.0", "backend": "test"}),
+                json!({"backend": {"op": "paste", "clipboard": "primary",
+                       "result": {"success": true, "data": "Grüße, world"}}}),
+                json!({"request": paste.to_string(), "response": {"success": true}}),
+                json!({"backend": {"op": "paste", "clipboard": "primary",
+                       "result": {"success": true, "data": "Grüße, world"}}}),
+                json!({"request": paste.to_string(), "response": {"success": true}}),
+            ],
+        );
+
+        let output = replay(&["--max-paste", "4"], &path);
+        let res = &responses(&output)[0];
+        assert!(!res.success);
+        assert!(res.message.as_ref().unwrap().starts_with("Payload too large"));
+
+        // Truncated on a character boundary
+        let output = replay(&["--max-paste", "4", "--oversized", "truncate"], &path);
+        let res = &responses(&output)[0];
+        assert_eq!(res.data.as_deref(), Some("Grü\n[truncated by clipipe]"));
+        assert!(res.truncated);
+    }
+
+    #[test]
+    fn change_token() {
+        // The token is a fixed hash of the contents, so it survives in recordings
+        let token = "94b600a0bb39f439";
+        let paste = json!({"action": "paste", "clipboard": "clipboard"});
+        let poll = json!({"action": "paste", "clipboard": "clipboard", "if_changed_since": token});
+        let pasted = |data| {
+            json!({"backend": {"op": "paste", "clipboard": "clipboard",
+                   "result": {"success": true, "data": data}}})
+        };
+        let path = recording(
+            "change_token.jsonl",
+            &[
+                json!({"version": "0.0.0", "backend": "test"}),
+                pasted("polled"),
+                json!({"request": paste.to_string(), "response": {"success": true}}),
+                pasted("polled"),
+                json!({"request": poll.to_string(), "response": {"success": true}}),
+                pasted("changed"),
+                json!({"request": poll.to_string(), "response": {"success": true}}),
+            ],
+        );
+
+        let output = replay(&[], &path);
+        let responses = responses(&output);
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0].data.as_deref(), Some("polled"));
+        assert_eq!(responses[0].token.as_deref(), Some(token));
+        // Unchanged contents aren't sent again
+        let unchanged = Response {
+            unchanged: true,
+            token: Some(token.into()),
+            ..Response::ok()
+        };
+        assert_eq!(responses[1], unchanged);
+        assert_eq!(responses[2].data.as_deref(), Some("changed"));
+        assert!(!responses[2].unchanged);
+        assert_ne!(responses[2].token.as_deref(), Some(token));
+    }
+
+    // The spill directory is XDG_RUNTIME_DIR on Linux
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn spill() {
+        let dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+        let copied = dir.join("clipipe-0-0.txt");
+        std::fs::write(&copied, "copied through a file").expect("Couldn't write file");
+        let copy = json!({"action": "copy", "clipboard": "clipboard", "file": copied});
+        // Linewise text from a file ends with a newline, as it does from lines
+        let linewise = dir.join("clipipe-0-4.txt");
+        std::fs::write(&linewise, "one\ntwo").expect("Couldn't write file");
+        let copy_linewise =
+            json!({"action": "copy", "clipboard": "clipboard", "file": linewise, "regtype": "V"});
+        let paste = json!({"action": "paste", "clipboard": "clipboard"});
+        let path = recording(
+            "spill.jsonl",
+            &[
+                json!({"version": "0.0.0", "backend": "test"}),
+                json!({"backend": {"op": "copy", "clipboard": "clipboard",
+                       "data": "copied through a file", "result": {"success": true}}}),
+                json!({"request": copy.to_string(), "response": {"success": true}}),
+                json!({"backend": {"op": "copy", "clipboard": "clipboard",
+                       "data": "one\ntwo\n", "regtype": "V", "result": {"success": true}}}),
+                json!({"request": copy_linewise.to_string(), "response": {"success": true}}),
+                json!({"backend": {"op": "paste", "clipboard": "clipboard",
+                       "result": {"success": true, "data": "pasted through a file"}}}),
+                json!({"request": paste.to_string(), "response": {"success": true}}),
+            ],
+        );
+
+        let output = replay(&["--spill", "8"], &path);
+        let responses = responses(&output);
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0], Response::ok());
+        assert_eq!(responses[1], Response::ok());
+        // The files are consumed
+        assert!(!copied.exists());
+        assert!(!linewise.exists());
+
+        let pasted = Path::new(responses[2].file.as_deref().expect("No file"));
+        assert_eq!(responses[2].size, Some(21));
+        assert!(responses[2].data.is_none());
+        let data = std::fs::read_to_string(pasted).expect("Couldn't read file");
+        std::fs::remove_file(pasted).expect("Couldn't remove file");
+        assert_eq!(data, "pasted through a file");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn spill_refused() {
+        let dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+        let misnamed = dir.join("spill-refused.txt");
+        let outside = dir.join("spill-refused");
+        std::fs::create_dir_all(&outside).expect("Couldn't create directory");
+        let outside = outside.join("clipipe-0-1.txt");
+        let link = dir.join("clipipe-0-2.txt");
+        let oversized = dir.join("clipipe-0-3.txt");
+        for path in [&misnamed, &outside] {
+            std::fs::write(path, "not for copying").expect("Couldn't write file");
+        }
+        // Larger than the maximum, but in a request under it
+        std::fs::write(&oversized, "x".repeat(300)).expect("Couldn't write file");
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(&misnamed, &link).expect("Couldn't create link");
+
+        let copy = |path: &Path| json!({"action": "copy", "clipboard": "clipboard", "file": path});
+        let requests = [&misnamed, &outside, &link, &oversized]
+            .map(|path| json!({"request": copy(path).to_string(), "response": {"success": false}}));
+        let mut entries = vec![json!({"version": "0.0.0", "backend": "test"})];
+        entries.extend(requests);
+        let path = recording("spill-refused.jsonl", &entries);
+
+        let output = replay(&["--max-request", "256"], &path);
+        let responses = responses(&output);
+        assert_eq!(responses.len(), 4);
+        assert!(responses.iter().all(|response| !response.success));
+        assert!(responses[0].message.as_ref().unwrap().contains("spill file"));
+        assert!(responses[3].message.as_ref().unwrap().contains("too large"));
+        // Refused files are left alone, and the link isn't followed
+        assert!(misnamed.exists());
+        assert!(outside.exists());
+        assert!(link.symlink_metadata().is_ok());
+        // Oversized files are still removed, without being read
+        assert!(!oversized.exists());
+        for path in [&misnamed, &outside, &link] {
+            std::fs::remove_file(path).expect("Couldn't remove file");
+        }
+    }
+
+    #[test]
+    fn protocol() {
+        let path = recording(
+            "protocol.jsonl",
+            &[
+                json!({"version": "0.0.0", "backend": "test"}),
+                json!({"request": r#"{"action": "paste", "clipboard": "bogus"}"#,
+                       "response": {"success": false}}),
+                json!({"request": r#"{"action": "bogus"}"#, "response": {"success": false}}),
+                json!({"request": r#"{"action": "schema"}"#, "response": {"success": true}}),
+            ],
+        );
+
+        let output = replay(&[], &path);
+        let responses = responses(&output);
+        assert_eq!(responses.len(), 3);
+        // Errors name the offending field
+        let message = responses[0].message.as_ref().unwrap();
+        assert!(message.contains("invalid `clipboard`"), "{}", message);
+        assert!(message.contains("bogus"), "{}", message);
+        let message = responses[1].message.as_ref().unwrap();
+        assert!(message.contains("unknown variant `bogus`"), "{}", message);
+        let schema = responses[2].schema.as_ref().expect("No schema");
+        assert!(schema["$defs"]["request"]["oneOf"].is_array());
+        assert!(schema["$defs"]["response"]["properties"]["success"].is_object());
+    }
+
+    #[test]
+    fn batch() {
+        let batch = json!({"action": "batch", "requests": [
+            {"action": "copy", "clipboard": "primary", "data": "first"},
+            {"action": "copy", "clipboard": "clipboard", "data": "second"},
+            {"action": "paste", "clipboard": "clipboard"},
+            {"action": "paste", "clipboard": "primary"},
+        ]});
+        let nested = json!({"action": "batch", "requests": [{"action": "batch", "requests": []}]});
+        let path = recording(
+            "batch.jsonl",
+            &[
+                json!({"version": "0.0.0", "backend": "test"}),
+                json!({"backend": {"op": "copy", "clipboard": "primary",
+                       "data": "first", "result": {"success": true}}}),
+                json!({"backend": {"op": "copy", "clipboard": "clipboard",
+                       "data": "second", "result": {"success": true}}}),
+                json!({"backend": {"op": "paste", "clipboard": "clipboard",
+                       "result": {"success": false, "message": "system error"}}}),
+                json!({"backend": {"op": "paste", "clipboard": "primary",
+                       "result": {"success": true, "data": "first"}}}),
+                json!({"request": batch.to_string(), "response": {"success": true}}),
+                json!({"request": nested.to_string(), "response": {"success": false}}),
+            ],
+        );
+
+        let output = replay(&[], &path);
+        let responses = responses(&output);
+        assert_eq!(responses.len(), 2);
+        assert!(responses[0].success);
+        let results = responses[0].results.as_ref().expect("No results");
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0], Response::ok());
+        assert_eq!(results[1], Response::ok());
+        // A failure doesn't stop the rest of the batch
+        assert_eq!(results[2].message.as_deref(), Some("system error"));
+        assert_eq!(results[3].data.as_deref(), Some("first"));
+        assert!(!responses[1].success);
+    }
+
+    #[test]
+    fn batch_redacted() {
+        let batch = json!({"action": "batch", "requests": [
+            {"action": "copy", "clipboard": "primary", "data": "SECRET1"},
+            {"action": "copy", "clipboard": "clipboard", "lines": ["SECRET3"], "regtype": "v"},
+            {"action": "paste", "clipboard": "primary"},
+            {"action": "paste", "clipboard": "clipboard", "lines": true},
+        ]});
+        let path = recording(
+            "batch-redacted.jsonl",
+            &[
+                json!({"version": "0.0.0", "backend": "test"}),
+                json!({"backend": {"op": "copy", "clipboard": "primary",
+                       "data": "SECRET1", "result": {"success": true}}}),
+                json!({"backend": {"op": "copy", "clipboard": "clipboard",
+                       "data": "SECRET3", "regtype": "v", "result": {"success": true}}}),
+                json!({"backend": {"op": "paste", "clipboard": "primary",
+                       "result": {"success": true, "data": "SECRET2"}}}),
+                json!({"backend": {"op": "paste", "clipboard": "clipboard",
+                       "result": {"success": true, "data": "SECRET4"}}}),
+                json!({"request": batch.to_string(), "response": {"success": true}}),
+            ],
+        );
+        let log = Path::new(env!("CARGO_TARGET_TMPDIR")).join("batch-redacted.log");
+        let _ = std::fs::remove_file(&log);
+
+        let log_file = log.to_str().unwrap();
+        let output = replay(&["--log-file", log_file, "--log-level", "debug"], &path);
+        let results = responses(&output).remove(0).results.expect("No results");
+        assert_eq!(results[2].data.as_deref(), Some("SECRET2"));
+        let logged = std::fs::read_to_string(&log).expect("Couldn't read log");
+        assert!(logged.contains("requests"));
+        assert!(!logged.contains("SECRET"), "{}", logged);
+    }
+
+    #[rstest]
+    // Like Neovim, linewise registers end with an empty line
+    #[case::linewise(json!(["one", "two", ""]), "V", "one\ntwo\n")]
+    #[case::charwise(json!(["one", "two"]), "v", "one\ntwo")]
+    #[case::empty_line(json!(["one", "", ""]), "V", "one\n\n")]
+    fn line_arrays(#[case] lines: Value, #[case] regtype: &str, #[case] data: &str) {
+        let copy = json!({"action": "copy", "lines": lines, "regtype": regtype});
+        let paste = json!({"action": "paste", "lines": true});
+        let path = recording(
+            &format!("lines-{}.jsonl", data.len()),
+            &[
+                json!({"version": "0.0.0", "backend": "test"}),
+                json!({"backend": {"op": "copy", "clipboard": "default",
+                       "data": data, "regtype": regtype, "result": {"success": true}}}),
+                json!({"request": copy.to_string(), "response": {"success": true}}),
+                json!({"backend": {"op": "paste", "clipboard": "default",
+                       "result": {"success": true, "data": data}}}),
+                json!({"request": paste.to_string(), "response": {"success": true}}),
+            ],
+        );
+
+        let output = replay(&[], &path);
+        let responses = responses(&output);
+        assert_eq!(responses.len(), 2);
+        // The backend saw the joined text
+        assert_eq!(responses[0], Response::ok());
+        assert_eq!(json!(responses[1].lines), lines);
+        assert_eq!(responses[1].regtype.as_deref(), Some(regtype));
+        assert!(responses[1].data.is_none());
+    }
+
+    #[test]
+    fn blockwise() {
+        // The backend keeps the register type, so the paste needn't guess it
+        let lines = json!(["ab", "cd", ""]);
+        let copy = json!({"action": "copy", "lines": lines, "regtype": "b"});
+        let paste = json!({"action": "paste", "lines": true});
+        let path = recording(
+            "blockwise.jsonl",
+            &[
+                json!({"version": "0.0.0", "backend": "test"}),
+                json!({"backend": {"op": "copy", "clipboard": "default",
+                       "data": "ab\ncd\n", "regtype": "b", "result": {"success": true}}}),
+                json!({"request": copy.to_string(), "response": {"success": true}}),
+                json!({"backend": {"op": "paste", "clipboard": "default",
+                       "result": {"success": true, "data": "ab\ncd\n", "regtype": "b"}}}),
+                json!({"request": paste.to_string(), "response": {"success": true}}),
+            ],
+        );
+
+        let output = replay(&[], &path);
+        let responses = responses(&output);
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0], Response::ok());
+        assert_eq!(json!(responses[1].lines), lines);
+        assert_eq!(responses[1].regtype.as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn linewise_data() {
+        // Linewise text ends with a newline whether it's sent as data or as lines
+        let paste = json!({"action": "paste", "lines": true});
+        let copied = json!({"backend": {"op": "copy", "clipboard": "default",
+                            "data": "a\n", "regtype": "V", "result": {"success": true}}});
+        let pasted = json!({"backend": {"op": "paste", "clipboard": "default",
+                            "result": {"success": true, "data": "a\n", "regtype": "V"}}});
+        let mut entries = vec![json!({"version": "0.0.0", "backend": "test"})];
+        for copy in [
+            json!({"action": "copy", "data": "a", "regtype": "V"}),
+            json!({"action": "copy", "lines": ["a"], "regtype": "V"}),
+        ] {
+            entries.push(copied.clone());
+            entries.push(json!({"request": copy.to_string(), "response": {"success": true}}));
+            entries.push(pasted.clone());
+            entries.push(json!({"request": paste.to_string(), "response": {"success": true}}));
+        }
+        let path = recording("linewise-data.jsonl", &entries);
+
+        let output = replay(&[], &path);
+        let responses = responses(&output);
+        assert_eq!(responses.len(), 4);
+        assert_eq!(responses[0], Response::ok());
+        assert_eq!(responses[2], Response::ok());
+        assert_eq!(responses[1], responses[3]);
+        assert_eq!(json!(responses[1].lines), json!(["a", ""]));
+    }
+
+    #[test]
+    fn fatal_error() {
+        let output = Command::new(env!("CARGO_BIN_EXE_clipipe"))
+            .arg("--bogus")
+            .stdin(Stdio::null())
+            .output()
+            .expect("Couldn't run clipipe");
+        assert_eq!(output.status.code(), Some(2));
+        let response =
+            Value::from_str(&String::from_utf8_lossy(&output.stdout)).expect("Invalid JSON");
+        assert_eq!(response["success"], Value::Bool(false));
+        assert_eq!(response["fatal"], Value::Bool(true));
+        assert_eq!(response["exit_code"], json!(2));
+        assert!(response["message"].as_str().unwrap().contains("--bogus"));
+    }
+
+    #[test]
+    fn query_ignores_config() {
+        let config = Path::new(env!("CARGO_TARGET_TMPDIR")).join("bogus-config.toml");
+        std::fs::write(&config, "bogus = true\n").expect("Couldn't write config");
+        for arg in ["--query", "--version", "--help"] {
+            let output = Command::new(env!("CARGO_BIN_EXE_clipipe"))
+                .arg(arg)
+                .env("CLIPIPE_CONFIG", &config)
+                .env("CLIPIPE_TIMEOUT", "abc")
+                .stdin(Stdio::null())
+                .output()
+                .expect("Couldn't run clipipe");
+            assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+        }
+        // Serving still reports the mistakes
+        let output = Command::new(env!("CARGO_BIN_EXE_clipipe"))
+            .env("CLIPIPE_CONFIG", &config)
+            .stdin(Stdio::null())
+            .output()
+            .expect("Couldn't run clipipe");
+        assert_eq!(output.status.code(), Some(3));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn content_length_framing() {
+        // Without a display server, the only response is the fatal error
+        let output = Command::new(env!("CARGO_BIN_EXE_clipipe"))
+            .args(["--framing", "content-length", "--backend", "x11"])
+            .env_remove("DISPLAY")
+            .stdin(Stdio::null())
+            .output()
+            .expect("Couldn't run clipipe");
+        assert_eq!(output.status.code(), Some(4));
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let (header, body) = stdout.split_once("\r\n\r\n").expect("No header");
+        assert_eq!(header, format!("Content-Length: {}", body.len()));
+        let response = Value::from_str(body).expect("Invalid JSON");
+        assert_eq!(response["fatal"], Value::Bool(true));
+    }
+
+    #[test]
+    fn content_length_requests() {
+        let path = recording(
+            "content-length.jsonl",
+            &[
+                json!({"version": "0.0.0", "backend": "test"}),
+                json!({"backend": {"op": "copy", "clipboard": "clipboard",
+                       "data": "framed", "result": {"success": true}}}),
+                json!({"backend": {"op": "paste", "clipboard": "primary",
+                       "result": {"success": true, "data": "framed ✓"}}}),
+            ],
+        );
+        // Bodies may span lines, and headers other than Content-Length are ignored
+        let mut input = vec![];
+        for request in [
+            json!({"action": "copy", "clipboard": "clipboard", "data": "framed"}),
+            json!({"action": "paste", "clipboard": "primary"}),
+        ] {
+            let body = serde_json::to_string_pretty(&request).unwrap();
+            write!(
+                input,
+                "Content-Length: {}\r\nContent-Type: application/json\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+        }
+
+        let output = replay_serve(&["--framing", "content-length"], &path, &input);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let mut stdout = output.stdout.as_slice();
+        let mut responses = vec![];
+        while !stdout.is_empty() {
+            let end = stdout.windows(4).position(|w| w == b"\r\n\r\n").expect("No header");
+            let header = std::str::from_utf8(&stdout[..end]).unwrap();
+            let size: usize = header
+                .strip_prefix("Content-Length: ")
+                .and_then(|size| size.parse().ok())
+                .expect("Bad header");
+            let body = &stdout[end + 4..end + 4 + size];
+            responses.push(serde_json::from_slice::<Value>(body).expect("Invalid JSON"));
+            stdout = &stdout[end + 4 + size..];
+        }
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0], json!({"success": true}));
+        assert_eq!(responses[1]["data"], json!("framed ✓"));
+    }
+
+    #[test]
+    fn content_length_too_long() {
+        let path = recording(
+            "content-length-too-long.jsonl",
+            &[json!({"version": "0.0.0", "backend": "test"})],
+        );
+        // Lengths beyond what's sent aren't allocated up front
+        for length in [u64::MAX, 1_000_000_000_000] {
+            let input = format!("Content-Length: {}\r\n\r\n{{}}", length);
+            let output = replay_serve(&["--framing", "content-length"], &path, input.as_bytes());
+            assert_eq!(output.status.code(), Some(5));
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let (header, body) = stdout.split_once("\r\n\r\n").expect("No header");
+            assert_eq!(header, format!("Content-Length: {}", body.len()));
+            let response = Value::from_str(body).expect("Invalid JSON");
+            assert_eq!(response["fatal"], Value::Bool(true));
+            assert!(response["message"].as_str().unwrap().contains("Content-Length"));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn msgpack_encoding() {
+        let output = Command::new(env!("CARGO_BIN_EXE_clipipe"))
+            .args(["--encoding", "msgpack", "--backend", "x11"])
+            .env_remove("DISPLAY")
+            .stdin(Stdio::null())
+            .output()
+            .expect("Couldn't run clipipe");
+        assert_eq!(output.status.code(), Some(4));
+        let mut stdout = output.stdout.as_slice();
+        let (response, size) = msgpack::read(&mut stdout, usize::MAX)
+            .expect("Invalid MessagePack")
+            .expect("No response");
+        assert_eq!(size, output.stdout.len());
+        assert_eq!(response["fatal"], Value::Bool(true));
+        assert_eq!(response["exit_code"], json!(4));
+
+        // Strings aren't escaped, and every length and integer form survives
+        let value = json!({
+            "data": format!("\"quoted\" \\ {}", "x".repeat(70000)),
+            "ints": [0, 127, 128, 65535, 65536, u64::MAX, -1, -32, -33, i64::MIN],
+            "nested": {"list": (0..20).collect::<Vec<_>>(), "empty": {}, "null": null},
+        });
+        let mut encoded = vec![];
+        msgpack::encode(&mut encoded, &value);
+        assert!(encoded.windows(10).any(|w| w == b"\"quoted\" \\"));
+        let decoded = msgpack::read(&mut encoded.as_slice(), usize::MAX).unwrap();
+        assert_eq!(decoded, Some((value, encoded.len())));
+
+        // Oversized values are read to the end, but not kept
+        let (_, size) = msgpack::read(&mut encoded.as_slice(), 100).unwrap().unwrap();
+        assert_eq!(size, encoded.len());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn msgpack_requests() {
+        let data = "binary \"payload\" ✓";
+        let path = recording(
+            "msgpack.jsonl",
+            &[
+                json!({"version": "0.0.0", "backend": "test"}),
+                json!({"backend": {"op": "copy", "clipboard": "clipboard",
+                       "data": data, "result": {"success": true}}}),
+                json!({"backend": {"op": "paste", "clipboard": "clipboard",
+                       "result": {"success": true, "data": data}}}),
+            ],
+        );
+        // A copy map whose data is a bin 8 value rather than a string
+        let mut input = vec![0x83];
+        for value in ["action", "copy", "clipboard", "clipboard", "data"] {
+            msgpack::encode(&mut input, &json!(value));
+        }
+        input.extend([0xc4, data.len() as u8]);
+        input.extend(data.as_bytes());
+        msgpack::encode(&mut input, &json!({"action": "paste", "clipboard": "clipboard"}));
+
+        let output = replay_serve(&["--encoding", "msgpack"], &path, &input);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let mut stdout = output.stdout.as_slice();
+        let mut responses = vec![];
+        while let Some((response, _)) = msgpack::read(&mut stdout, usize::MAX).unwrap() {
+            responses.push(response);
+        }
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0], json!({"success": true}));
+        assert_eq!(responses[1]["data"], json!(data));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn msgpack_too_long() {
+        let path = recording(
+            "msgpack-too-long.jsonl",
+            &[json!({"version": "0.0.0", "backend": "test"})],
+        );
+        // A str 32 and a bin 32 claiming 4 GiB, which isn't allocated up front
+        for tag in [0xdb, 0xc6] {
+            let input = [&[tag, 0xff, 0xff, 0xff, 0xff][..], b"abc"].concat();
+            let output = replay_serve(&["--encoding", "msgpack"], &path, &input);
+            assert_eq!(output.status.code(), Some(5));
+            let (response, _) = msgpack::read(&mut output.stdout.as_slice(), usize::MAX)
+                .expect("Invalid MessagePack")
+                .expect("No response");
+            assert_eq!(response["fatal"], Value::Bool(true));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn rpc_mode() {
+        // The channel only carries msgpack-RPC, so a fatal error isn't written to it
+        let output = Command::new(env!("CARGO_BIN_EXE_clipipe"))
+            .args(["--rpc", "--backend", "x11"])
+            .env_remove("DISPLAY")
+            .stdin(Stdio::null())
+            .output()
+            .expect("Couldn't run clipipe");
+        assert_eq!(output.status.code(), Some(4));
+        assert!(output.stdout.is_empty());
+
+        // Connects to the Neovim server, and announces itself once its backend is running
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Couldn't listen");
+        let address = listener.local_addr().unwrap().to_string();
+        let child = Command::new(env!("CARGO_BIN_EXE_clipipe"))
+            .args(["--nvim", &address, "--backend", "x11"])
+            .env_remove("DISPLAY")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Couldn't run clipipe");
+        let (mut stream, _) = listener.accept().expect("No connection");
+        let output = child.wait_with_output().expect("Couldn't wait for clipipe");
+        assert_eq!(output.status.code(), Some(4));
+        let mut received = vec![];
+        std::io::Read::read_to_end(&mut stream, &mut received).expect("Couldn't read");
+        assert!(received.is_empty());
+        assert!(output.stdout.is_empty());
+    }
+
+    #[test]
+    fn vim_channel_requests() {
+        let path = recording(
+            "vim-channel.jsonl",
+            &[
+                json!({"version": "0.0.0", "backend": "test"}),
+                json!({"backend": {"op": "copy", "clipboard": "clipboard",
+                       "data": "from vim", "result": {"success": true}}}),
+                json!({"backend": {"op": "paste", "clipboard": "clipboard",
+                       "result": {"success": true, "data": "from vim"}}}),
+            ],
+        );
+        let input = [
+            json!([7, {"action": "copy", "clipboard": "clipboard", "data": "from vim"}]),
+            json!([42, {"action": "paste", "clipboard": "clipboard"}]),
+            json!([9, {"action": "bogus"}]),
+        ]
+        .map(|message| format!("{}\n", message))
+        .concat();
+
+        let output = replay_serve(&["--vim"], &path, input.as_bytes());
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let messages: Vec<Value> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|line| Value::from_str(line).expect("Invalid JSON"))
+            .collect();
+        assert_eq!(messages.len(), 3);
+        // Each response is paired with the ID of its request
+        assert_eq!(messages[0], json!([7, {"success": true}]));
+        assert_eq!(messages[1][0], json!(42));
+        assert_eq!(messages[1][1]["data"], json!("from vim"));
+        assert_eq!(messages[2][0], json!(9));
+        assert_eq!(messages[2][1]["success"], Value::Bool(false));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn rpc_requests() {
+        let path = recording(
+            "rpc.jsonl",
+            &[
+                json!({"version": "0.0.0", "backend": "test"}),
+                json!({"backend": {"op": "copy", "clipboard": "clipboard",
+                       "data": "via rpc", "result": {"success": true}}}),
+                json!({"backend": {"op": "paste", "clipboard": "clipboard",
+                       "result": {"success": true, "data": "via rpc"}}}),
+            ],
+        );
+        let mut input = vec![];
+        for message in [
+            json!([0, 1, "clipipe_copy", [{"clipboard": "clipboard", "data": "via rpc"}]]),
+            // Malformed calls are answered if they have a message ID, and otherwise ignored
+            json!([0, 2, "clipipe_copy", ["not a map"]]),
+            json!([0, "no id", "clipipe_paste", []]),
+            json!([0, 3, "clipipe_paste", [{"clipboard": "clipboard"}]]),
+        ] {
+            msgpack::encode(&mut input, &message);
+        }
+
+        let output = replay_serve(&["--rpc"], &path, &input);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let mut stdout = output.stdout.as_slice();
+        let mut messages = vec![];
+        while let Some((message, _)) = msgpack::read(&mut stdout, usize::MAX).unwrap() {
+            messages.push(message);
+        }
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0], json!([1, 1, null, {"success": true}]));
+        assert_eq!(messages[1][1], json!(2));
+        assert!(messages[1][2][1].as_str().unwrap().contains("Invalid msgpack-RPC call"));
+        assert_eq!(messages[1][3], Value::Null);
+        assert_eq!(messages[2][1], json!(3));
+        assert_eq!(messages[2][3]["data"], json!("via rpc"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn vim_channel() {
+        // Messages that don't answer a request go to the channel callback, with ID 0
+        let output = Command::new(env!("CARGO_BIN_EXE_clipipe"))
+            .args(["--vim", "--backend", "x11"])
+            .env_remove("DISPLAY")
+            .stdin(Stdio::null())
+            .output()
+            .expect("Couldn't run clipipe");
+        assert_eq!(output.status.code(), Some(4));
+        let message =
+            Value::from_str(&String::from_utf8_lossy(&output.stdout)).expect("Invalid JSON");
+        assert_eq!(message[0], json!(0));
+        assert_eq!(message[1]["fatal"], Value::Bool(true));
+    }
 }
@@ -27,5 +27,7 @@ macro_rules! test {
 test! {
     basic,
     startup_timeout,
-    paste_timeout
+    paste_timeout,
+    rpc,
+    regtype
 }
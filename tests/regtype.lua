@@ -0,0 +1,10 @@
+local clipipe = require 'clipipe'
+local lines = { "foo", "bar" }
+
+clipipe.enable()
+vim.fn.setreg("+", lines, "l")
+assert_eq(vim.fn.getregtype("+"), "V")
+assert_eq(vim.fn.getreg("+", 1, true), lines)
+vim.fn.setreg("+", lines, "c")
+assert_eq(vim.fn.getregtype("+"), "v")
+assert_eq(vim.fn.getreg("+", 1, true), lines)
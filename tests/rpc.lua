@@ -0,0 +1,8 @@
+local clipipe = require 'clipipe'
+local text = "foo\nbar"
+
+setup { rpc = true }
+clipipe.enable()
+vim.fn.setreg("+", text)
+local res = vim.fn.getreg("+")
+assert_eq(res, text)